@@ -0,0 +1,226 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::environment::ShellEnv;
+
+/// What can go wrong evaluating an arithmetic expression
+#[derive(Debug, PartialEq)]
+pub enum ArithError {
+    /// Something that doesn't fit the grammar, with the text where it went wrong
+    Syntax(String),
+    /// A variable whose value isn't an integer
+    InvalidNumber(String),
+    DivisionByZero,
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithError::Syntax(token) => write!(f, "syntax error in expression (error token is \"{}\")", token),
+            ArithError::InvalidNumber(value) => write!(f, "{}: invalid number", value),
+            ArithError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for ArithError {}
+
+/// Evaluate an integer expression, as in `$((...))`. Supports `+ - * / %`,
+/// unary `+` and `-`, parentheses, and variables by bare name or `$NAME`.
+/// Unset or empty variables count as 0.
+pub fn eval_arith(expr: &str, env: &ShellEnv) -> Result<i64, ArithError> {
+    let mut parser = Parser { chars: expr.chars().peekable(), env };
+    let value = parser.expression()?;
+
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(_) => Err(ArithError::Syntax(parser.chars.collect())),
+    }
+}
+
+/// A recursive-descent parser that evaluates as it goes. The grammar is:
+///
+/// ```text
+/// expression := term (('+' | '-') term)*
+/// term       := factor (('*' | '/' | '%') factor)*
+/// factor     := ('+' | '-') factor | number | variable | '(' expression ')'
+/// ```
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    env: &'a ShellEnv,
+}
+
+impl Parser<'_> {
+    fn expression(&mut self) -> Result<i64, ArithError> {
+        let mut value = self.term()?;
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some('+') => {
+                    self.chars.next();
+                    value = value.wrapping_add(self.term()?);
+                }
+                Some('-') => {
+                    self.chars.next();
+                    value = value.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, ArithError> {
+        let mut value = self.factor()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.chars.peek() {
+                Some(&op) if matches!(op, '*' | '/' | '%') => op,
+                _ => return Ok(value),
+            };
+            self.chars.next();
+
+            let right = self.factor()?;
+            value = match op {
+                '*' => value.wrapping_mul(right),
+                _ if right == 0 => return Err(ArithError::DivisionByZero),
+                '/' => value.wrapping_div(right),
+                _ => value.wrapping_rem(right),
+            };
+        }
+    }
+
+    fn factor(&mut self) -> Result<i64, ArithError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(self.factor()?.wrapping_neg())
+            }
+            Some('+') => {
+                self.chars.next();
+                self.factor()
+            }
+            Some('(') => {
+                self.chars.next();
+                let value = self.expression()?;
+                self.skip_whitespace();
+                match self.chars.next() {
+                    Some(')') => Ok(value),
+                    Some(other) => Err(ArithError::Syntax(other.to_string())),
+                    None => Err(ArithError::Syntax(")".to_string())),
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take_while(|c| c.is_ascii_alphanumeric());
+                digits.parse().map_err(|_| ArithError::InvalidNumber(digits))
+            }
+            Some(&c) if c == '$' || c == '_' || c.is_ascii_alphabetic() => {
+                if c == '$' {
+                    self.chars.next();
+                }
+                let name = self.take_while(|c| c == '_' || c.is_ascii_alphanumeric());
+                if name.is_empty() {
+                    return Err(ArithError::Syntax("$".to_string()));
+                }
+                self.variable(&name)
+            }
+            Some(_) => Err(ArithError::Syntax(self.chars.clone().collect())),
+            None => Err(ArithError::Syntax(String::new())),
+        }
+    }
+
+    /// The value of a variable, which must hold an integer
+    fn variable(&self, name: &str) -> Result<i64, ArithError> {
+        let value = self.env.get_var(name).map_or("", |value| value.trim());
+        if value.is_empty() {
+            return Ok(0);
+        }
+        value.parse().map_err(|_| ArithError::InvalidNumber(value.to_string()))
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !keep(c) {
+                break;
+            }
+            taken.push(c);
+            self.chars.next();
+        }
+        taken
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, ArithError> {
+        eval_arith(expr, &ShellEnv::empty())
+    }
+
+    #[test]
+    fn test_numbers_and_operators() {
+        assert_eq!(eval("42"), Ok(42));
+        assert_eq!(eval("2 + 3"), Ok(5));
+        assert_eq!(eval("2 - 3"), Ok(-1));
+        assert_eq!(eval("6 * 7"), Ok(42));
+        assert_eq!(eval("7 / 2"), Ok(3));
+        assert_eq!(eval("-7 / 2"), Ok(-3));
+        assert_eq!(eval("7 % 3"), Ok(1));
+        assert_eq!(eval("  1+2  "), Ok(3));
+    }
+
+    #[test]
+    fn test_precedence_and_parentheses() {
+        assert_eq!(eval("2 + 3 * 4"), Ok(14));
+        assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("100 / 10 / 5"), Ok(2));
+        assert_eq!(eval("2 * (3 + (4 - 1)) % 5"), Ok(2));
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        assert_eq!(eval("-5"), Ok(-5));
+        assert_eq!(eval("--5"), Ok(5));
+        assert_eq!(eval("+5"), Ok(5));
+        assert_eq!(eval("3 * -(2 + 1)"), Ok(-9));
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut env = ShellEnv::empty();
+        env.set_var("x", "5");
+        env.set_var("neg", " -2 ");
+        env.set_var("word", "abc");
+
+        assert_eq!(eval_arith("x * 2", &env), Ok(10));
+        assert_eq!(eval_arith("$x + neg", &env), Ok(3));
+        assert_eq!(eval_arith("unset + 1", &env), Ok(1));
+        assert_eq!(eval_arith("word + 1", &env), Err(ArithError::InvalidNumber("abc".to_string())));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(eval("1 / 0"), Err(ArithError::DivisionByZero));
+        assert_eq!(eval("1 % (2 - 2)"), Err(ArithError::DivisionByZero));
+        assert_eq!(ArithError::DivisionByZero.to_string(), "division by zero");
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(eval("1 +"), Err(ArithError::Syntax(String::new())));
+        assert_eq!(eval("(1 + 2"), Err(ArithError::Syntax(")".to_string())));
+        assert_eq!(eval("1 2"), Err(ArithError::Syntax("2".to_string())));
+        assert_eq!(eval("2 ^ 3"), Err(ArithError::Syntax("^ 3".to_string())));
+        assert_eq!(eval("12abc"), Err(ArithError::InvalidNumber("12abc".to_string())));
+        assert_eq!(eval(""), Err(ArithError::Syntax(String::new())));
+    }
+}
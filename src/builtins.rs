@@ -1,18 +1,61 @@
 use std::collections::HashMap;
 use std::env;
-use std::io::Write;
+use std::io::{BufRead, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 
+use crate::arith;
+use crate::command_processor::{is_var_name, run_file};
 use crate::environment::ShellEnv;
+use crate::external::{is_executable, resolve_in_path};
+use crate::jobs::{parse_job_spec, set_foreground};
+use crate::shell_io::ShellIo;
+use crate::signals::set_interrupted;
 
 // Simple enum for builtin result
 #[derive(PartialEq, Debug)]
 pub enum ShellAction {
     Continue,
-    Exit,
+    /// Leave the shell with this status
+    Exit(i32),
 }
 
 pub type BuiltinFn = fn(&[&str], &mut ShellEnv, &mut dyn Write, &mut dyn Write) -> ShellAction;
-pub type BuiltinMap = HashMap<&'static str, BuiltinFn>;
+/// A builtin that also looks at the shell's builtin table, such as `type`
+pub type TableBuiltinFn = fn(&[&str], &mut ShellEnv, &BuiltinMap, &mut dyn Write, &mut dyn Write) -> ShellAction;
+pub type BuiltinMap = HashMap<&'static str, Builtin>;
+
+/// The function behind a builtin
+#[derive(Clone, Copy)]
+pub enum BuiltinFunc {
+    Plain(BuiltinFn),
+    WithTable(TableBuiltinFn),
+}
+
+/// A builtin command along with its help text
+pub struct Builtin {
+    pub func: BuiltinFunc,
+    /// One line on what it does, for `help`
+    pub summary: &'static str,
+    /// How to call it, for `help NAME`
+    pub usage: &'static str,
+}
+
+impl Builtin {
+    /// Run the builtin, handing it `builtins` if it needs the table
+    pub fn run(
+        &self,
+        args: &[&str],
+        env: &mut ShellEnv,
+        builtins: &BuiltinMap,
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> ShellAction {
+        match self.func {
+            BuiltinFunc::Plain(func) => func(args, env, out, err),
+            BuiltinFunc::WithTable(func) => func(args, env, builtins, out, err),
+        }
+    }
+}
 
 
 pub fn builtin_cd(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
@@ -26,6 +69,7 @@ pub fn builtin_cd(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err:
             Some(path) => path.clone(),
             None => {
                 let _ = writeln!(err, "cd: OLDPWD not set");
+                env.last_status = 1;
                 return ShellAction::Continue;
             }
         }
@@ -33,13 +77,21 @@ pub fn builtin_cd(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err:
         args[0].to_string()
     };
 
+    change_dir("cd", Path::new(&target), env, err);
+    ShellAction::Continue
+}
+
+/// Change directory and update `OLDPWD` and `PWD`, reporting failures
+/// under `name`. Sets the status and returns whether it worked.
+fn change_dir(name: &str, target: &Path, env: &mut ShellEnv, err: &mut dyn Write) -> bool {
     // Save old PWD before changing
     let old_pwd = env::current_dir().unwrap();
 
     // Try to change directory
-    if let Err(e) = env::set_current_dir(&target) {
-        let _ = writeln!(err, "cd: {}", e);
-        return ShellAction::Continue;
+    if let Err(e) = env::set_current_dir(target) {
+        let _ = writeln!(err, "{}: {}: {}", name, target.display(), e);
+        env.last_status = 1;
+        return false;
     }
 
     // Update environment variables
@@ -47,57 +99,940 @@ pub fn builtin_cd(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err:
     env.set_var("OLDPWD", &old_pwd.to_string_lossy());
     env.set_var("PWD", &new_pwd.to_string_lossy());
 
-    ShellAction::Continue
+    env.last_status = 0;
+    true
 }
 
-fn builtin_pwd(_: &[&str], _: &mut ShellEnv, out: &mut dyn Write, _: &mut dyn Write) -> ShellAction {
+fn builtin_pwd(_: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _: &mut dyn Write) -> ShellAction {
     let _ = writeln!(out, "{}", std::env::current_dir().unwrap().display());
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+/// `pushd DIR` saves the current directory and changes to DIR; with no
+/// argument it swaps the current directory with the top of the stack
+fn builtin_pushd(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    let current = env::current_dir().unwrap();
+    let target = match args {
+        [] => match env.dir_stack.pop() {
+            Some(top) => top,
+            None => {
+                let _ = writeln!(err, "pushd: no other directory");
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        },
+        [dir] => PathBuf::from(dir),
+        _ => {
+            let _ = writeln!(err, "usage: pushd [DIR]");
+            env.last_status = 2;
+            return ShellAction::Continue;
+        }
+    };
+
+    if change_dir("pushd", &target, env, err) {
+        env.dir_stack.push(current);
+        print_dir_stack(env, out);
+    } else if args.is_empty() {
+        // Put back the entry we were trying to swap to
+        env.dir_stack.push(target);
+    }
+    ShellAction::Continue
+}
+
+/// `popd` removes the top of the directory stack and changes to it
+fn builtin_popd(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    if !args.is_empty() {
+        let _ = writeln!(err, "usage: popd");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    }
+
+    let Some(top) = env.dir_stack.last().cloned() else {
+        let _ = writeln!(err, "popd: directory stack empty");
+        env.last_status = 1;
+        return ShellAction::Continue;
+    };
+
+    if change_dir("popd", &top, env, err) {
+        env.dir_stack.pop();
+        print_dir_stack(env, out);
+    }
     ShellAction::Continue
 }
 
-fn builtin_echo(args: &[&str], _: &mut ShellEnv, out: &mut dyn Write, _: &mut dyn Write) -> ShellAction {
-    writeln!(out, "{}", args.join(" ")).unwrap();
+fn builtin_dirs(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    if !args.is_empty() {
+        let _ = writeln!(err, "usage: dirs");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    }
+
+    print_dir_stack(env, out);
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+/// Print the current directory followed by the stack, most recent first
+fn print_dir_stack(env: &ShellEnv, out: &mut dyn Write) {
+    let current = env::current_dir().unwrap();
+    let dirs: Vec<String> = std::iter::once(&current)
+        .chain(env.dir_stack.iter().rev())
+        .map(|dir| dir.display().to_string())
+        .collect();
+    let _ = writeln!(out, "{}", dirs.join(" "));
+}
+
+/// `echo [-neE] [ARG...]`: `-n` drops the newline, `-e` turns on
+/// backslash escapes and `-E` turns them back off. Flags only count before
+/// the first operand, and `--` ends them.
+fn builtin_echo(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _: &mut dyn Write) -> ShellAction {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut operands = args;
+    while let Some((first, rest)) = operands.split_first() {
+        if *first == "--" {
+            operands = rest;
+            break;
+        }
+        let Some(flags) = first.strip_prefix('-') else {
+            break;
+        };
+        if flags.is_empty() || !flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        operands = rest;
+    }
+
+    let mut text = operands.join(" ");
+    if escapes {
+        let (interpreted, stop) = interpret_escapes(&text);
+        text = interpreted;
+        // `\c` means no more output at all, newline included
+        if stop {
+            newline = false;
+        }
+    }
+    if newline {
+        text.push('\n');
+    }
+    let _ = out.write_all(text.as_bytes());
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+/// `printf FORMAT [ARG...]` fills `%s`, `%d` and `%c` in FORMAT from the
+/// arguments, with `%%` for a literal `%` and the same backslash escapes as
+/// `echo -e`. The format is reused until every argument has been taken.
+fn builtin_printf(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    let Some((format, args)) = args.split_first() else {
+        let _ = writeln!(err, "usage: printf FORMAT [ARG...]");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    };
+
+    let (format, stop) = interpret_escapes(format);
+    let mut args = args.iter();
+    let mut text = String::new();
+    env.last_status = 0;
+    loop {
+        let mut converted = false;
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => text.push('%'),
+                // Missing arguments are empty, or zero for %d
+                Some('s') => text.push_str(args.next().unwrap_or(&"")),
+                Some('c') => text.extend(args.next().and_then(|arg| arg.chars().next())),
+                Some('d') => {
+                    let arg = args.next().unwrap_or(&"0");
+                    let number = arg.trim().parse::<i64>().unwrap_or_else(|_| {
+                        let _ = writeln!(err, "printf: {}: invalid number", arg);
+                        env.last_status = 1;
+                        0
+                    });
+                    text.push_str(&number.to_string());
+                }
+                Some(other) => {
+                    let _ = writeln!(err, "printf: %{}: invalid format character", other);
+                    env.last_status = 1;
+                    break;
+                }
+                None => {
+                    let _ = writeln!(err, "printf: missing format character");
+                    env.last_status = 1;
+                    break;
+                }
+            }
+            converted = true;
+        }
+
+        // Go round again only while there are arguments left to use
+        if stop || !converted || env.last_status != 0 || args.len() == 0 {
+            break;
+        }
+    }
+
+    let _ = out.write_all(text.as_bytes());
     ShellAction::Continue
 }
 
-fn builtin_exit(_: &[&str], _: &mut ShellEnv, _: &mut dyn Write, _: &mut dyn Write) -> ShellAction {
-    ShellAction::Exit
+/// Replace `echo -e` escapes such as `\n` and `\t`. Also says whether a
+/// `\c` cut the text short.
+fn interpret_escapes(text: &str) -> (String, bool) {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => result.push('\\'),
+            Some('a') => result.push('\x07'),
+            Some('b') => result.push('\x08'),
+            Some('c') => return (result, true),
+            Some('e') => result.push('\x1b'),
+            Some('f') => result.push('\x0c'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('v') => result.push('\x0b'),
+            Some('0') => {
+                // Up to three octal digits
+                let mut value = 0u32;
+                for _ in 0..3 {
+                    match chars.peek().and_then(|d| d.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                result.push(char::from_u32(value).unwrap_or('\0'));
+            }
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    (result, false)
+}
+
+/// `exit [N]` leaves the shell with status N, taken modulo 256, or with
+/// the last command's status
+fn builtin_exit(args: &[&str], env: &mut ShellEnv, _: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    match args {
+        [] => ShellAction::Exit(env.last_status),
+        [code] => match code.parse::<i64>() {
+            Ok(code) => ShellAction::Exit(code.rem_euclid(256) as i32),
+            Err(_) => {
+                let _ = writeln!(err, "exit: {}: numeric argument required", code);
+                ShellAction::Exit(255)
+            }
+        },
+        _ => {
+            // Like bash, don't leave when it's unclear what was meant
+            let _ = writeln!(err, "exit: too many arguments");
+            env.last_status = 1;
+            ShellAction::Continue
+        }
+    }
+}
+
+/// `set` lists every variable, `set VAR` prints one and `set VAR VALUE`
+/// assigns it
+pub fn builtin_set(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    env.last_status = 0;
+    match args {
+        [] => {
+            // Shell-local ones too, unlike env; sorted for a stable listing
+            let mut vars: Vec<(&String, &String)> = env.vars.iter().collect();
+            vars.sort();
+            for (name, value) in vars {
+                let _ = writeln!(out, "{}={}", name, value);
+            }
+        }
+        [name] => match env.get_var(name) {
+            Some(value) => {
+                let _ = writeln!(out, "{}={}", name, value);
+            }
+            None => {
+                let _ = writeln!(err, "set: {}: not set", name);
+                env.last_status = 1;
+            }
+        },
+        [name, value] => env.set_var(name, value),
+        _ => {
+            let _ = writeln!(err, "usage: set [VAR [VALUE]]");
+            env.last_status = 2;
+        }
+    }
+    ShellAction::Continue
 }
 
-pub fn builtin_set(args: &[&str], env: &mut ShellEnv, _: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
-    if args.len() != 2 {
-        let _ = writeln!(err, "usage: set VAR VALUE");
+/// `let EXPR...` evaluates each arithmetic expression in turn; one like
+/// `NAME=EXPR` also stores the value, and `NAME+=EXPR` (or `-=`, `*=`,
+/// `/=`, `%=`) updates it. Spaces around the `=` are fine, as in
+/// `let "x = 1"`. The status is 0 if the last value isn't zero, like a C
+/// condition.
+pub fn builtin_let(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    if args.is_empty() {
+        let _ = writeln!(err, "usage: let EXPR...");
+        env.last_status = 2;
         return ShellAction::Continue;
     }
-    env.set_var(args[0], args[1]);
+
+    let mut value = 0;
+    for arg in args {
+        let (name, expr) = match split_let_assignment(arg) {
+            Some((name, expr)) => (Some(name), expr),
+            None => (None, arg.to_string()),
+        };
+        let expr = expr.as_str();
+        value = match arith::eval_arith(expr, env) {
+            Ok(value) => value,
+            Err(e) => {
+                let _ = writeln!(err, "let: {}: {}", expr, e);
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        };
+        if let Some(name) = name {
+            env.set_var(name, &value.to_string());
+        }
+    }
+
+    env.last_status = if value == 0 { 1 } else { 0 };
     ShellAction::Continue
 }
 
+/// Split a `let` argument like `x = 1` or `x += 1` into the variable and
+/// the expression giving its new value, or `None` if it isn't an assignment
+fn split_let_assignment(arg: &str) -> Option<(&str, String)> {
+    let (target, expr) = arg.split_once('=')?;
+    let expr = expr.trim();
+    let target = target.trim();
+    if is_var_name(target) {
+        return Some((target, expr.to_string()));
+    }
+
+    // `x op= expr` is `x = x op (expr)`
+    let op = target.chars().last().filter(|op| "+-*/%".contains(*op))?;
+    let name = target[..target.len() - 1].trim_end();
+    is_var_name(name).then(|| (name, format!("{} {} ({})", name, op, expr)))
+}
+
 pub fn builtin_unset(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
     if args.len() != 1 {
         let _ = writeln!(err, "usage: unset VAR");
+        env.last_status = 2;
         return ShellAction::Continue;
     }
     env.unset_var(args[0]);
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+pub fn builtin_export(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    // With no arguments list the exported variables, sorted
+    if args.is_empty() {
+        let mut vars: Vec<(&String, &String)> = env.exported_vars().collect();
+        vars.sort();
+        for (name, value) in vars {
+            let _ = writeln!(out, "export {}='{}'", name, value);
+        }
+    }
+
+    for arg in args {
+        // `export NAME=value` sets and exports in one go
+        let name = match arg.split_once('=') {
+            Some((name, value)) => {
+                env.set_var(name, value);
+                name
+            }
+            None => arg,
+        };
+        env.export_var(name);
+    }
+
+    env.last_status = 0;
     ShellAction::Continue
 }
 
 pub fn builtin_env(_args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
-    for (k, v) in &env.vars {
+    // The variables live in a HashMap, so sort them for a stable listing
+    let mut pairs: Vec<(&String, &String)> = env.exported_vars().collect();
+    pairs.sort();
+    for (k, v) in pairs {
         let _ = writeln!(out, "{}={}", k, v);
     }
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+pub fn builtin_alias(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    env.last_status = 0;
+
+    // With no arguments list every alias, sorted so the output is stable
+    if args.is_empty() {
+        let mut names: Vec<&String> = env.aliases.keys().collect();
+        names.sort();
+        for name in names {
+            let _ = writeln!(out, "alias {}='{}'", name, env.aliases[name]);
+        }
+        return ShellAction::Continue;
+    }
+
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                env.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match env.aliases.get(*arg) {
+                Some(value) => {
+                    let _ = writeln!(out, "alias {}='{}'", arg, value);
+                }
+                None => {
+                    let _ = writeln!(err, "alias: {}: not found", arg);
+                    env.last_status = 1;
+                }
+            },
+        }
+    }
+    ShellAction::Continue
+}
+
+pub fn builtin_unalias(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    if args.is_empty() {
+        let _ = writeln!(err, "usage: unalias -a | NAME...");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    }
+    if args[0] == "-a" {
+        env.aliases.clear();
+        env.last_status = 0;
+        return ShellAction::Continue;
+    }
+
+    // Remove what we can, complaining about each name that isn't there
+    env.last_status = 0;
+    for arg in args {
+        if env.aliases.remove(*arg).is_none() {
+            let _ = writeln!(err, "unalias: {}: not found", arg);
+            env.last_status = 1;
+        }
+    }
+    ShellAction::Continue
+}
+
+pub fn builtin_jobs(_args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    // Check every job once so the listing and the cleanup agree
+    let done: Vec<bool> = env.jobs.iter_mut().map(|job| job.is_done()).collect();
+    let count = env.jobs.len();
+
+    for (i, job) in env.jobs.iter().enumerate() {
+        // Like bash, + marks the current (latest) job and - the one before
+        let marker = if i + 1 == count {
+            '+'
+        } else if i + 2 == count {
+            '-'
+        } else {
+            ' '
+        };
+        if done[i] {
+            let _ = writeln!(out, "[{}]{}  Done  {}", job.id, marker, job.cmdline);
+        } else {
+            let _ = writeln!(out, "[{}]{}  Running  {} &", job.id, marker, job.cmdline);
+        }
+    }
+
+    // Finished jobs have been reported, so drop them from the table
+    let mut done = done.into_iter();
+    env.jobs.retain(|_| !done.next().unwrap());
+
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+pub fn builtin_fg(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    // Default to the most recent job
+    let index = match args.first() {
+        Some(spec) => parse_job_spec(spec).and_then(|id| env.jobs.iter().position(|job| job.id == id)),
+        None => env.jobs.len().checked_sub(1),
+    };
+
+    let Some(index) = index else {
+        let _ = match args.first() {
+            Some(spec) => writeln!(err, "fg: {}: no such job", spec),
+            None => writeln!(err, "fg: no such job"),
+        };
+        env.last_status = 1;
+        return ShellAction::Continue;
+    };
+
+    let mut job = env.jobs.remove(index);
+    let _ = writeln!(out, "{}", job.cmdline);
+    let _ = out.flush();
+
+    // Give the job the terminal, so it can read it and Ctrl-C reaches it,
+    // and wake it in case it was stopped
+    let pgid = job.pgid as libc::pid_t;
+    let shell_group = if pgid > 0 {
+        // SAFETY: kill has no memory-safety requirements
+        unsafe { libc::kill(-pgid, libc::SIGCONT) };
+        set_foreground(pgid)
+    } else {
+        None
+    };
+    env.last_status = job.wait();
+    if let Some(group) = shell_group {
+        set_foreground(group);
+        if env.last_status == 128 + libc::SIGINT {
+            set_interrupted();
+        }
+    }
+    ShellAction::Continue
+}
+
+/// Signals `kill` knows by name, without the `SIG` prefix
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+];
+
+/// Turn `9`, `KILL` or `SIGKILL` (in any case) into a signal number
+fn parse_signal(name: &str) -> Option<libc::c_int> {
+    if let Ok(number) = name.parse::<libc::c_int>() {
+        return (0..=64).contains(&number).then_some(number);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS.iter().find(|(known, _)| *known == name).map(|&(_, number)| number)
+}
+
+pub fn builtin_kill(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    // An optional -SIGNAL comes first; the default is SIGTERM
+    let (signal, targets) = match args.split_first() {
+        Some((flag, rest)) if flag.len() > 1 && flag.starts_with('-') => match parse_signal(&flag[1..]) {
+            Some(signal) => (signal, rest),
+            None => {
+                let _ = writeln!(err, "kill: {}: invalid signal specification", &flag[1..]);
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        },
+        _ => (libc::SIGTERM, args),
+    };
+    if targets.is_empty() {
+        let _ = writeln!(err, "usage: kill [-SIGNAL] PID | %JOB...");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    }
+
+    env.last_status = 0;
+    for target in targets {
+        // A job spec signals the job's process group, a negative pid
+        let pid: libc::pid_t = if target.starts_with('%') {
+            match parse_job_spec(target).and_then(|id| env.jobs.iter().find(|job| job.id == id)) {
+                Some(job) if job.pgid > 0 => -(job.pgid as libc::pid_t),
+                _ => {
+                    let _ = writeln!(err, "kill: {}: no such job", target);
+                    env.last_status = 1;
+                    continue;
+                }
+            }
+        } else {
+            match target.parse::<libc::pid_t>() {
+                Ok(pid) if pid > 0 => pid,
+                _ => {
+                    let _ = writeln!(err, "kill: {}: arguments must be process or job IDs", target);
+                    env.last_status = 1;
+                    continue;
+                }
+            }
+        };
+
+        // SAFETY: kill has no memory-safety requirements
+        if unsafe { libc::kill(pid, signal) } != 0 {
+            let _ = writeln!(err, "kill: ({}) - {}", pid, std::io::Error::last_os_error());
+            env.last_status = 1;
+        }
+    }
+    ShellAction::Continue
+}
+
+/// The shell runs `source` itself, with its own streams (see
+/// `source_file`); this is for calls that come here directly, and gives
+/// the script in-memory streams that are copied to `out` and `err`
+pub fn builtin_source(
+    args: &[&str],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> ShellAction {
+    let mut io = ShellIo { stdout: Some(Vec::new()), stderr: Some(Vec::new()), ..ShellIo::default() };
+    let action = source_file(args, env, builtins, &mut io);
+    let _ = out.write_all(&io.stdout.unwrap_or_default());
+    let _ = err.write_all(&io.stderr.unwrap_or_default());
+    action
+}
+
+/// `source FILE` runs the commands in a file in this shell, with the
+/// streams in `io`
+pub fn source_file(args: &[&str], env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> ShellAction {
+    let Some(file) = args.first() else {
+        io.builtin_error(env, "usage: source FILE");
+        env.last_status = 2;
+        return ShellAction::Continue;
+    };
+
+    // The script runs in this shell, so its commands see the same builtins,
+    // and an exit in the script exits the shell
+    env.last_status = 0;
+    match run_file(Path::new(file), env, builtins, io) {
+        Ok(action) => action,
+        Err(e) => {
+            match e.kind() {
+                ErrorKind::NotFound => io.builtin_error(env, format_args!("source: {}: No such file or directory", file)),
+                _ => io.builtin_error(env, format_args!("source: {}: {}", file, e)),
+            };
+            env.last_status = 1;
+            ShellAction::Continue
+        }
+    }
+}
+
+pub fn builtin_type(
+    args: &[&str],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> ShellAction {
+    env.last_status = 0;
+
+    // Check in the order the shell would run things: aliases, then
+    // builtins, then programs on PATH
+    for name in args {
+        if let Some(value) = env.aliases.get(*name) {
+            let _ = writeln!(out, "{} is aliased to `{}'", name, value);
+        } else if builtins.contains_key(name) {
+            let _ = writeln!(out, "{} is a shell builtin", name);
+        } else if let Some(path) = resolve_in_path(name, env).filter(|path| is_executable(path)) {
+            let _ = writeln!(out, "{} is {}", name, path.display());
+        } else {
+            let _ = writeln!(err, "type: {}: not found", name);
+            env.last_status = 1;
+        }
+    }
+
+    ShellAction::Continue
+}
+
+pub fn builtin_history(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    // Show everything by default, or just the last N entries
+    let count = match args.first() {
+        Some(&"-c") => {
+            env.history.clear();
+            env.last_status = 0;
+            return ShellAction::Continue;
+        }
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                let _ = writeln!(err, "history: {}: numeric argument required", arg);
+                env.last_status = 2;
+                return ShellAction::Continue;
+            }
+        },
+        None => env.history.len(),
+    };
+
+    // Entries are numbered from 1, like bash
+    let start = env.history.len().saturating_sub(count);
+    for (i, entry) in env.history.iter().enumerate().skip(start) {
+        let _ = writeln!(out, "{:>5}  {}", i + 1, entry);
+    }
+
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+/// `clear` wipes the screen and puts the cursor in the top left corner
+pub fn builtin_clear(_args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    let _ = write!(out, "\x1b[2J\x1b[H");
+    let _ = out.flush();
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+pub fn builtin_which(args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    env.last_status = 0;
+
+    // Names that can't be found print nothing but fail the command
+    for name in args {
+        // Only programs count, so a name given as a path must be one too
+        match resolve_in_path(name, env).filter(|path| is_executable(path)) {
+            Some(path) => {
+                let _ = writeln!(out, "{}", path.display());
+            }
+            None => env.last_status = 1,
+        }
+    }
+
+    ShellAction::Continue
+}
+
+pub fn builtin_read(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    read_line_into(args, env, &mut std::io::stdin().lock(), err)
+}
+
+/// The work behind `read`, taking its input from `input` so it can be
+/// tested. With no names the line goes into REPLY. Each name takes one
+/// field, split on the characters in IFS, and the last takes whatever is
+/// left. The status is 1 at end of input, which lets a `while read` loop
+/// finish.
+pub fn read_line_into(args: &[&str], env: &mut ShellEnv, input: &mut dyn BufRead, err: &mut dyn Write) -> ShellAction {
+    let mut names = args;
+    if let Some(&"-p") = names.first() {
+        let Some(prompt) = names.get(1) else {
+            let _ = writeln!(err, "usage: read [-p PROMPT] [NAME...]");
+            env.last_status = 2;
+            return ShellAction::Continue;
+        };
+        // Like bash, the prompt goes to stderr so it isn't mixed into output
+        let _ = write!(err, "{}", prompt);
+        let _ = err.flush();
+        names = &names[2..];
+    }
+    let names = if names.is_empty() { &["REPLY"][..] } else { names };
+
+    let mut line = String::new();
+    env.last_status = match input.read_line(&mut line) {
+        Ok(0) => 1,
+        Ok(_) => 0,
+        Err(e) => {
+            let _ = writeln!(err, "read: {}", e);
+            1
+        }
+    };
+    if line.ends_with('\n') {
+        line.pop();
+    }
+
+    // Whitespace separators also gather up around the fields
+    let ifs = env.ifs().to_string();
+    let is_separator = |c: char| ifs.contains(c);
+    let is_blank = |c: char| ifs.contains(c) && c.is_whitespace();
+
+    let mut rest = line.trim_matches(is_blank);
+    for (i, name) in names.iter().enumerate() {
+        let value = if i + 1 == names.len() {
+            std::mem::take(&mut rest)
+        } else {
+            let (field, remainder) = rest.split_once(is_separator).unwrap_or((rest, ""));
+            rest = remainder.trim_start_matches(is_blank);
+            field
+        };
+        env.set_var(name, value);
+    }
+
+    ShellAction::Continue
+}
+
+pub fn builtin_test(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    report_test(evaluate_test(args), "test", env, err)
+}
+
+/// `true` does nothing, successfully
+pub fn builtin_true(_args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    env.last_status = 0;
+    ShellAction::Continue
+}
+
+/// `false` does nothing, unsuccessfully
+pub fn builtin_false(_args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, _err: &mut dyn Write) -> ShellAction {
+    env.last_status = 1;
+    ShellAction::Continue
+}
+
+/// `[ EXPR ]`, the same as `test EXPR` apart from the closing bracket
+pub fn builtin_bracket(args: &[&str], env: &mut ShellEnv, _out: &mut dyn Write, err: &mut dyn Write) -> ShellAction {
+    let result = match args.split_last() {
+        Some((&"]", expr)) => evaluate_test(expr),
+        _ => Err("missing `]'".to_string()),
+    };
+    report_test(result, "[", env, err)
+}
+
+/// Turn the result of a test into a status: 0 for true, 1 for false and
+/// 2 (with a message) for a malformed expression
+fn report_test(result: Result<bool, String>, name: &str, env: &mut ShellEnv, err: &mut dyn Write) -> ShellAction {
+    env.last_status = match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(message) => {
+            let _ = writeln!(err, "{}: {}", name, message);
+            2
+        }
+    };
+    ShellAction::Continue
+}
+
+/// Evaluate a `test` expression. Like POSIX test, what an expression
+/// means depends on how many arguments it has, and a leading `!` negates
+/// the rest.
+fn evaluate_test(args: &[&str]) -> Result<bool, String> {
+    match args {
+        [] => Ok(false),
+        // With three arguments a binary operator wins, so `! = x` compares
+        [left, op, right] if TEST_BINARY_OPS.contains(op) => test_binary(left, op, right),
+        ["!", rest @ ..] if !rest.is_empty() => evaluate_test(rest).map(|result| !result),
+        [string] => Ok(!string.is_empty()),
+        [op, operand] => {
+            let path = Path::new(operand);
+            match *op {
+                "-e" => Ok(path.exists()),
+                "-f" => Ok(path.is_file()),
+                "-d" => Ok(path.is_dir()),
+                "-z" => Ok(operand.is_empty()),
+                "-n" => Ok(!operand.is_empty()),
+                _ => Err(format!("{}: unary operator expected", op)),
+            }
+        }
+        [_, op, _] => Err(format!("{}: binary operator expected", op)),
+        _ => Err("too many arguments".to_string()),
+    }
+}
+
+/// The operators `test` accepts between two operands
+const TEST_BINARY_OPS: [&str; 8] = ["=", "!=", "-eq", "-ne", "-lt", "-le", "-gt", "-ge"];
+
+/// Compare two operands with one of `TEST_BINARY_OPS`
+fn test_binary(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    match op {
+        "=" => Ok(left == right),
+        "!=" => Ok(left != right),
+        _ => {
+            let (left, right) = (test_integer(left)?, test_integer(right)?);
+            Ok(match op {
+                "-eq" => left == right,
+                "-ne" => left != right,
+                "-lt" => left < right,
+                "-le" => left <= right,
+                "-gt" => left > right,
+                _ => left >= right,
+            })
+        }
+    }
+}
+
+/// Parse an operand of an integer comparison
+fn test_integer(value: &str) -> Result<i64, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{}: integer expression expected", value))
+}
+
+pub fn builtin_help(
+    args: &[&str],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> ShellAction {
+    env.last_status = 0;
+
+    // With no arguments give a one-line summary of every builtin
+    if args.is_empty() {
+        let mut names: Vec<&&str> = builtins.keys().collect();
+        names.sort();
+        let _ = writeln!(out, "lsh builtins (help NAME for more):");
+        for name in names {
+            let _ = writeln!(out, "  {:<9} {}", name, builtins[*name].summary);
+        }
+        return ShellAction::Continue;
+    }
+
+    for name in args {
+        match builtins.get(name) {
+            Some(builtin) => {
+                let _ = writeln!(out, "{}\n    {}", builtin.usage, builtin.summary);
+            }
+            None => {
+                let _ = writeln!(err, "help: no help topics match `{}'", name);
+                env.last_status = 1;
+            }
+        }
+    }
+
     ShellAction::Continue
 }
 
 pub fn builtins() -> BuiltinMap {
+    use BuiltinFunc::{Plain, WithTable};
+
     let mut map: BuiltinMap = BuiltinMap::new();
-    map.insert("cd", builtin_cd);
-    map.insert("pwd", builtin_pwd);
-    map.insert("echo", builtin_echo);
-    map.insert("exit", builtin_exit);
-    map.insert("set", builtin_set);
-    map.insert("unset", builtin_unset);
-    map.insert("env", builtin_env);
+    let mut add = |name, func, usage, summary| {
+        map.insert(name, Builtin { func, summary, usage });
+    };
+    add("cd", Plain(builtin_cd), "cd [DIR | -]", "Change directory (HOME by default, - for the previous one)");
+    add("pwd", Plain(builtin_pwd), "pwd", "Print the current directory");
+    add("pushd", Plain(builtin_pushd), "pushd [DIR]", "Save the current directory and change to DIR, or swap with the top saved one");
+    add("popd", Plain(builtin_popd), "popd", "Change back to the most recently saved directory");
+    add("dirs", Plain(builtin_dirs), "dirs", "Print the current directory and the saved ones");
+    add("echo", Plain(builtin_echo), "echo [-neE] [ARG...]", "Print the arguments separated by spaces");
+    add("printf", Plain(builtin_printf), "printf FORMAT [ARG...]", "Print the arguments as FORMAT (%s, %d, %c) says");
+    add("exit", Plain(builtin_exit), "exit [N]", "Leave the shell with status N, or the last command's status");
+    add("set", Plain(builtin_set), "set [VAR [VALUE]]", "Set a shell variable, or show one or all of them");
+    add("let", Plain(builtin_let), "let EXPR...", "Evaluate arithmetic, assigning with NAME=EXPR");
+    add("unset", Plain(builtin_unset), "unset VAR", "Remove a variable");
+    add("export", Plain(builtin_export), "export [NAME[=VALUE]...]", "Pass variables on to commands, or list exported ones");
+    add("env", Plain(builtin_env), "env", "Print the exported variables");
+    add("alias", Plain(builtin_alias), "alias [NAME[=VALUE]...]", "Define aliases, or show them");
+    add("unalias", Plain(builtin_unalias), "unalias -a | NAME...", "Remove aliases, or all of them with -a");
+    add("jobs", Plain(builtin_jobs), "jobs", "List background jobs");
+    add("fg", Plain(builtin_fg), "fg [%N]", "Wait for a background job in the foreground");
+    add("kill", Plain(builtin_kill), "kill [-SIGNAL] PID | %JOB...", "Send a signal (TERM by default) to processes or jobs");
+    add("source", WithTable(builtin_source), "source FILE", "Run the commands in a file in this shell");
+    add(".", WithTable(builtin_source), ". FILE", "Run the commands in a file in this shell");
+    add("type", WithTable(builtin_type), "type NAME...", "Show whether each name is an alias, builtin or program");
+    add("history", Plain(builtin_history), "history [-c | N]", "Show the command history, or clear it");
+    add("which", Plain(builtin_which), "which NAME...", "Print the path of each program on PATH");
+    add("read", Plain(builtin_read), "read [-p PROMPT] [NAME...]", "Read a line of input into variables");
+    add("test", Plain(builtin_test), "test EXPR", "Check files, strings and numbers, setting the status");
+    add("[", Plain(builtin_bracket), "[ EXPR ]", "Check files, strings and numbers, setting the status");
+    add("clear", Plain(builtin_clear), "clear", "Clear the terminal screen");
+    add("true", Plain(builtin_true), "true", "Do nothing, successfully");
+    add("false", Plain(builtin_false), "false", "Do nothing, unsuccessfully");
+    add("help", WithTable(builtin_help), "help [NAME...]", "Describe the builtins");
     map
 }
 
@@ -115,283 +1050,1339 @@ mod tests {
     #[test]
     fn test_exit_returns_exit_action() {
         let builtins = builtins();
-        let exit_fn = builtins["exit"];
+        let exit = &builtins["exit"];
         let mut buf = Vec::new();
         let mut err_buf = Vec::new();
         let mut env = ShellEnv::empty();
-        let result = exit_fn(&[], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Exit);
-        assert!(buf.is_empty());
+        let result = exit.run(&[], &mut env, &builtins, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Exit(0));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_exit_parses_code() {
+        let exit_code = |args: &[&str]| {
+            let mut env = ShellEnv::empty();
+            env.last_status = 7;
+            let mut err_buf = Vec::new();
+            let action = builtin_exit(args, &mut env, &mut Vec::new(), &mut err_buf);
+            (action, env.last_status, String::from_utf8(err_buf).unwrap())
+        };
+
+        assert_eq!(exit_code(&[]), (ShellAction::Exit(7), 7, String::new()));
+        assert_eq!(exit_code(&["2"]), (ShellAction::Exit(2), 7, String::new()));
+        assert_eq!(exit_code(&["256"]), (ShellAction::Exit(0), 7, String::new()));
+        assert_eq!(exit_code(&["-1"]), (ShellAction::Exit(255), 7, String::new()));
+        assert_eq!(
+            exit_code(&["abc"]),
+            (ShellAction::Exit(255), 7, "exit: abc: numeric argument required\n".to_string())
+        );
+        assert_eq!(
+            exit_code(&["1", "2"]),
+            (ShellAction::Continue, 1, "exit: too many arguments\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_let_assigns_and_sets_status() {
+        let mut env = ShellEnv::empty();
+
+        builtin_let(&["x=1+2", "y=x * (4 - 1) % 5"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("x").unwrap(), "3");
+        assert_eq!(env.get_var("y").unwrap(), "4");
+        assert_eq!(env.last_status, 0);
+
+        // A zero result is false
+        builtin_let(&["z=x-3"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("z").unwrap(), "0");
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_let_with_spaces_and_update_operators() {
+        let mut env = ShellEnv::empty();
+
+        // As `let "z = 3"` and `let 'x += 1'` arrive once quotes are gone
+        builtin_let(&["z = 3", "x=10", "x += 1", " x *= z - 1 "], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("z").unwrap(), "3");
+        assert_eq!(env.get_var("x").unwrap(), "22");
+        assert_eq!(env.last_status, 0);
+
+        builtin_let(&["x-=2", "x /= 4", "x%=3"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("x").unwrap(), "2");
+
+        // Not a variable on the left, so not an assignment
+        let mut err_buf = Vec::new();
+        builtin_let(&["1 += 2"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(env.last_status, 1);
+        assert!(String::from_utf8(err_buf).unwrap().starts_with("let: 1 += 2: "));
+    }
+
+    #[test]
+    fn test_let_errors() {
+        let mut env = ShellEnv::empty();
+        let mut err_buf = Vec::new();
+
+        builtin_let(&["x=1/0"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "let: 1/0: division by zero\n");
+        assert_eq!(env.get_var("x"), None);
+        assert_eq!(env.last_status, 1);
+
+        builtin_let(&[], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 2);
+    }
+
+    #[test]
+    fn test_echo_to_closed_output_does_not_panic() {
+        // Like a pipe whose reader has gone, as in `echo ... | head -c1`
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut env = ShellEnv::empty();
+        let result = builtin_echo(&["hello"], &mut env, &mut Closed, &mut Vec::new());
+        assert_eq!(result, ShellAction::Continue);
+    }
+
+    #[test]
+    fn test_echo_writes_to_output() {
+        let builtins = builtins();
+        let echo = &builtins["echo"];
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::empty();
+        let result = echo.run(&["hello", "world"], &mut env, &builtins, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        let output = String::from_utf8(buf).unwrap();
+        assert_eq!(output.trim(), "hello world");
+    }
+
+    fn echo_output(args: &[&str]) -> String {
+        let mut buf = Vec::new();
+        builtin_echo(args, &mut ShellEnv::empty(), &mut buf, &mut Vec::new());
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_echo_flags() {
+        assert_eq!(echo_output(&[]), "\n");
+        assert_eq!(echo_output(&["a\\tb"]), "a\\tb\n");
+        assert_eq!(echo_output(&["-n", "a", "b"]), "a b");
+        assert_eq!(echo_output(&["-e", "a\\tb\\n\\\\"]), "a\tb\n\\\n");
+        assert_eq!(echo_output(&["-ne", "a\\nb"]), "a\nb");
+        assert_eq!(echo_output(&["-n", "-e", "x\\0101"]), "xA");
+        assert_eq!(echo_output(&["-e", "-E", "a\\tb"]), "a\\tb\n");
+        assert_eq!(echo_output(&["-e", "a\\cb", "c"]), "a");
+        assert_eq!(echo_output(&["-e", "a\\qb"]), "a\\qb\n");
+    }
+
+    fn printf_output(args: &[&str]) -> (String, String, i32) {
+        let mut env = ShellEnv::empty();
+        let mut out = Vec::new();
+        let mut err_buf = Vec::new();
+        builtin_printf(args, &mut env, &mut out, &mut err_buf);
+        (String::from_utf8(out).unwrap(), String::from_utf8(err_buf).unwrap(), env.last_status)
+    }
+
+    #[test]
+    fn test_printf_formats() {
+        assert_eq!(printf_output(&["%s-%s\\n", "a", "b"]).0, "a-b\n");
+        assert_eq!(printf_output(&["%d\\n", "42"]).0, "42\n");
+        assert_eq!(printf_output(&["%c|%c\\t%%\\n", "xyz", "-1"]).0, "x|-\t%\n");
+        assert_eq!(printf_output(&["no conversions\\n", "ignored"]).0, "no conversions\n");
+        assert_eq!(printf_output(&["[%s][%d]\\n"]).0, "[][0]\n");
+    }
+
+    #[test]
+    fn test_printf_reuses_format() {
+        assert_eq!(printf_output(&["%s\\n", "a", "b", "c"]).0, "a\nb\nc\n");
+        // The last round is padded out with empty arguments
+        assert_eq!(printf_output(&["%s=%d ", "x", "1", "y"]).0, "x=1 y=0 ");
+    }
+
+    #[test]
+    fn test_printf_errors() {
+        assert_eq!(
+            printf_output(&["%d\\n", "abc"]),
+            ("0\n".to_string(), "printf: abc: invalid number\n".to_string(), 1)
+        );
+        assert_eq!(
+            printf_output(&["a%qb", "x"]),
+            ("a".to_string(), "printf: %q: invalid format character\n".to_string(), 1)
+        );
+        assert_eq!(printf_output(&[]).2, 2);
+    }
+
+    #[test]
+    fn test_echo_flags_only_before_operands() {
+        assert_eq!(echo_output(&["a", "-n"]), "a -n\n");
+        assert_eq!(echo_output(&["--", "-n"]), "-n\n");
+        assert_eq!(echo_output(&["-n", "--", "-e"]), "-e");
+        assert_eq!(echo_output(&["-x", "a"]), "-x a\n");
+        assert_eq!(echo_output(&["-", "a"]), "- a\n");
+    }
+
+    #[test]
+    #[serial]
+    fn test_pwd_prints_current_directory() {
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::empty();
+        let result = builtin_pwd(&[], &mut env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+
+        let output = String::from_utf8(buf).unwrap();
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(output.trim(), cwd.display().to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_changes_directory_and_pwd_reflects_it() {
+        // Save original directory
+        let orig_dir = env::current_dir().unwrap();
+
+        // Create a temporary directory
+        let tmp_dir = orig_dir.join("tmp_test_dir");
+        fs::create_dir_all(&tmp_dir).unwrap();
+
+        // cd into the new directory
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::empty();
+        let result = builtin_cd(&[tmp_dir.to_str().unwrap()], &mut env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+
+        // pwd should now reflect the new directory
+        let mut pwd_buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::empty();
+        builtin_pwd(&[], &mut env, &mut pwd_buf, &mut err_buf);
+        let output = String::from_utf8(pwd_buf).unwrap();
+        assert_eq!(output.trim(), tmp_dir.display().to_string());
+
+        // cd back to original directory
+        env::set_current_dir(&orig_dir).unwrap();
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_no_args_goes_to_home() {
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::new();
+
+        // Save the original dir so we can restore it
+        let original_dir = std::env::current_dir().unwrap();
+
+        let home = std::env::var("HOME").unwrap_or_else(|_| "/".to_string());
+        let result = builtin_cd(&[], &mut env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+
+        let new_dir = std::env::current_dir().unwrap();
+        assert_eq!(new_dir, std::path::Path::new(&home));
+
+        // Clean up
+        std::env::set_current_dir(&original_dir).unwrap();
+
+        let stdout = String::from_utf8(buf).unwrap();
+        let stderr = String::from_utf8(err_buf).unwrap();
+
+        assert!(stdout.is_empty());
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_invalid_path_prints_error() {
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut env = ShellEnv::empty();
+        let result = builtin_cd(&["/definitely/not/a/real/path"], &mut env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        let output = String::from_utf8(err_buf).unwrap();
+        assert!(output.starts_with("cd: "));
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_success_sets_status_zero() {
+        let _guard = CwdGuard::new();
+
+        let dir = tempdir().unwrap();
+        let mut env = ShellEnv::empty();
+        env.last_status = 1;
+
+        builtin_cd(&[dir.path().to_str().unwrap()], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
+    }
+
+
+    /// Restores the current working directory when dropped.
+    struct CwdGuard {
+        original: PathBuf,
+    }
+
+    impl CwdGuard {
+        fn new() -> Self {
+            let original = env::current_dir().expect("failed to get current_dir");
+            CwdGuard { original }
+        }
+    }
+
+    impl Drop for CwdGuard {
+        fn drop(&mut self) {
+            // Best-effort restore; ignore errors
+            let _ = env::set_current_dir(&self.original);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_pushd_popd_walk_the_stack() {
+        let _guard = CwdGuard::new();
+
+        let start = env::current_dir().unwrap();
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        let path1 = dir1.path().canonicalize().unwrap();
+        let path2 = dir2.path().canonicalize().unwrap();
+        let mut shell_env = ShellEnv::empty();
+        let mut err = Vec::new();
+
+        builtin_pushd(&[path1.to_str().unwrap()], &mut shell_env, &mut Vec::new(), &mut err);
+        let mut out = Vec::new();
+        builtin_pushd(&[path2.to_str().unwrap()], &mut shell_env, &mut out, &mut err);
+        assert_eq!(env::current_dir().unwrap(), path2);
+        assert_eq!(shell_env.get_var("PWD").unwrap(), path2.to_str().unwrap());
+        assert_eq!(shell_env.get_var("OLDPWD").unwrap(), path1.to_str().unwrap());
+        let expected = format!("{} {} {}\n", path2.display(), path1.display(), start.display());
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = Vec::new();
+        builtin_dirs(&[], &mut shell_env, &mut out, &mut err);
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        builtin_popd(&[], &mut shell_env, &mut Vec::new(), &mut err);
+        assert_eq!(env::current_dir().unwrap(), path1);
+        assert_eq!(shell_env.get_var("OLDPWD").unwrap(), path2.to_str().unwrap());
+
+        builtin_popd(&[], &mut shell_env, &mut Vec::new(), &mut err);
+        assert_eq!(env::current_dir().unwrap(), start);
+        assert!(shell_env.dir_stack.is_empty());
+        assert!(err.is_empty());
+        assert_eq!(shell_env.last_status, 0);
+
+        builtin_popd(&[], &mut shell_env, &mut Vec::new(), &mut err);
+        assert_eq!(String::from_utf8(err).unwrap(), "popd: directory stack empty\n");
+        assert_eq!(shell_env.last_status, 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_pushd_to_missing_dir_keeps_stack() {
+        let _guard = CwdGuard::new();
+
+        let start = env::current_dir().unwrap();
+        let mut shell_env = ShellEnv::empty();
+        let mut err = Vec::new();
+
+        builtin_pushd(&["/nonexistent/dir"], &mut shell_env, &mut Vec::new(), &mut err);
+        assert!(String::from_utf8(err).unwrap().starts_with("pushd: /nonexistent/dir: "));
+        assert_eq!(shell_env.last_status, 1);
+        assert!(shell_env.dir_stack.is_empty());
+        assert_eq!(env::current_dir().unwrap(), start);
+    }
+
+    #[test]
+    #[serial]
+    fn test_pushd_without_args_swaps_top_two() {
+        let _guard = CwdGuard::new();
+
+        let start = env::current_dir().unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap();
+        let mut shell_env = ShellEnv::empty();
+        let mut err = Vec::new();
+
+        builtin_pushd(&[], &mut shell_env, &mut Vec::new(), &mut err);
+        assert_eq!(String::from_utf8(err).unwrap(), "pushd: no other directory\n");
+        assert_eq!(shell_env.last_status, 1);
+
+        builtin_pushd(&[path.to_str().unwrap()], &mut shell_env, &mut Vec::new(), &mut Vec::new());
+        builtin_pushd(&[], &mut shell_env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env::current_dir().unwrap(), start);
+        assert_eq!(shell_env.dir_stack, std::slice::from_ref(&path));
+
+        builtin_pushd(&[], &mut shell_env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env::current_dir().unwrap(), path);
+        assert_eq!(shell_env.dir_stack, [start]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_dash_goes_to_oldpwd() {
+        // Ensure we restore cwd even if the test panics
+        let _guard = CwdGuard::new();
+
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut shell_env = ShellEnv::empty();
+
+        // Two temporary directories to toggle between
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        let path1 = dir1.path().to_path_buf();
+        let path2 = dir2.path().to_path_buf();
+
+        // Step 1: cd into dir1
+        let result = builtin_cd(&[path1.to_str().unwrap()], &mut shell_env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        assert_eq!(
+            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
+            std::fs::canonicalize(&path1).unwrap()
+        );
+
+        // Step 2: cd into dir2
+        let result = builtin_cd(&[path2.to_str().unwrap()], &mut shell_env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        assert_eq!(
+            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
+            std::fs::canonicalize(&path2).unwrap()
+        );
+
+        // Step 3: cd -
+        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        assert_eq!(
+            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
+            std::fs::canonicalize(&path1).unwrap()
+        );
+
+        // Step 4: cd - again → back to dir2
+        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+        assert_eq!(
+            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
+            std::fs::canonicalize(&path2).unwrap()
+        );
+
+        // No unexpected stderr
+        let stderr = String::from_utf8(err_buf).unwrap();
+        assert!(stderr.is_empty());
+
+        // _guard drops here and restores the original cwd
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_dash_goes_writes_to_err_old_pwd_not_set() {
+        // Ensure we restore cwd even if the test panics
+        let _guard = CwdGuard::new();
+
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+        let mut shell_env = ShellEnv::empty();
+
+        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
+        assert_eq!(result, ShellAction::Continue);
+
+        // No unexpected stderr
+        let stderr = String::from_utf8(err_buf).unwrap();
+        assert_eq!(stderr.trim(), "cd: OLDPWD not set");
+    }
+
+    #[test]
+    fn test_builtin_env_prints_all_vars() {
+        let mut env = ShellEnv::empty();
+        env.set_var("USER", "testuser");
+        env.set_var("HOME", "/tmp");
+        env.set_var("PATH", "/usr/bin");
+        env.export_var("USER");
+        env.export_var("HOME");
+
+        let mut output = Cursor::new(Vec::new());
+
+        let result = builtin_env(&[], &mut env, &mut output, &mut std::io::sink());
+        assert!(matches!(result, ShellAction::Continue));
+
+        let output_str = String::from_utf8(output.into_inner()).unwrap();
+
+        // Each env var should appear as key=value followed by newline
+        assert!(output_str.contains("USER=testuser"));
+        assert!(output_str.contains("HOME=/tmp"));
+        assert!(output_str.contains("PATH=/usr/bin"));
+
+        // Should print one per line, so 3 lines total
+        let lines: Vec<&str> = output_str.lines().collect();
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_builtin_set_sets_the_env() {
+
+        let mut env = ShellEnv::empty();
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+
+        let result = builtin_set(&["hello", "world"], &mut env, &mut buf, &mut err_buf);
+        assert!(matches!(result, ShellAction::Continue));
+
+        assert_eq!(env.get_var("hello").unwrap(), "world");
+    }
+
+    #[test]
+    fn test_builtin_set_with_too_many_args_raises_error() {
+
+        let mut env = ShellEnv::empty();
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+
+        let result = builtin_set(&["A", "b", "c"], &mut env, &mut buf, &mut err_buf);
+        assert!(matches!(result, ShellAction::Continue));
+
+        let stderr = String::from_utf8(err_buf).unwrap();
+        assert_eq!(stderr.trim(), "usage: set [VAR [VALUE]]");
+        assert_eq!(env.last_status, 2);
+        assert!(env.get_var("A").is_none());
+    }
+
+    #[test]
+    fn test_builtin_set_without_args_lists_all_vars() {
+        let mut env = ShellEnv::empty();
+        env.vars.clear();
+        builtin_set(&["ZED", "last"], &mut env, &mut Vec::new(), &mut Vec::new());
+        builtin_set(&["ALPHA", "first"], &mut env, &mut Vec::new(), &mut Vec::new());
+        builtin_export(&["ALPHA"], &mut env, &mut Vec::new(), &mut Vec::new());
+
+        let mut out = Vec::new();
+        builtin_set(&[], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "ALPHA=first\nZED=last\n");
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_builtin_set_with_one_arg_prints_that_var() {
+        let mut env = ShellEnv::empty();
+        env.set_var("GREETING", "hello there");
+
+        let mut out = Vec::new();
+        builtin_set(&["GREETING"], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "GREETING=hello there\n");
+        assert_eq!(env.last_status, 0);
+
+        let mut err = Vec::new();
+        builtin_set(&["MISSING"], &mut env, &mut Vec::new(), &mut err);
+        assert_eq!(String::from_utf8(err).unwrap(), "set: MISSING: not set\n");
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_builtin_unset_with_args_unsets_the_var() {
+
+        let mut env = ShellEnv::empty();
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+
+        env.set_var("hello", "world");
+
+        let result = builtin_unset(&["hello"], &mut env, &mut buf, &mut err_buf);
+        assert!(matches!(result, ShellAction::Continue));
+
+        assert_eq!(env.get_var("hello"), None);
+    }
+
+    #[test]
+    fn test_builtin_unset_without_args_raises_error() {
+
+        let mut env = ShellEnv::empty();
+        let mut buf = Vec::new();
+        let mut err_buf = Vec::new();
+
+        let result = builtin_unset(&[], &mut env, &mut buf, &mut err_buf);
+        assert!(matches!(result, ShellAction::Continue));
+
+        // No unexpected stderr
+        let stderr = String::from_utf8(err_buf).unwrap();
+        assert_eq!(stderr.trim(), "usage: unset VAR");
+        assert_eq!(env.last_status, 2);
+    }
+
+    #[test]
+    fn test_successful_builtins_set_status_zero() {
+        let mut env = ShellEnv::empty();
+        let builtins = builtins();
+
+        for (name, args) in [("echo", vec!["hi"]), ("set", vec!["A", "b"]), ("unset", vec!["A"]), ("env", vec![])] {
+            env.last_status = 1;
+            builtins[name].run(&args, &mut env, &builtins, &mut Vec::new(), &mut Vec::new());
+            assert_eq!(env.last_status, 0, "{} should succeed", name);
+        }
+    }
+
+    #[test]
+    fn test_alias_defines_and_lists() {
+        let mut env = ShellEnv::empty();
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+
+        builtin_alias(&["ll=ls -la", "la=ls -a"], &mut env, &mut out, &mut err);
+        assert_eq!(env.aliases["ll"], "ls -la");
+        assert_eq!(env.last_status, 0);
+
+        builtin_alias(&[], &mut env, &mut out, &mut err);
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output, "alias la='ls -a'\nalias ll='ls -la'\n");
+        assert!(err.is_empty());
+    }
+
+    #[test]
+    fn test_alias_prints_single_alias() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+
+        builtin_alias(&["ll"], &mut env, &mut out, &mut err);
+        assert_eq!(String::from_utf8(out).unwrap(), "alias ll='ls -la'\n");
+
+        builtin_alias(&["nope"], &mut env, &mut Vec::new(), &mut err);
+        assert_eq!(String::from_utf8(err).unwrap().trim(), "alias: nope: not found");
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_unalias_removes_alias() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+
+        builtin_unalias(&["ll"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert!(env.aliases.is_empty());
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_unalias_without_args_raises_error() {
+        let mut env = ShellEnv::empty();
+        let mut err_buf = Vec::new();
+
+        builtin_unalias(&[], &mut env, &mut Vec::new(), &mut err_buf);
+        let stderr = String::from_utf8(err_buf).unwrap();
+        assert_eq!(stderr.trim(), "usage: unalias -a | NAME...");
+    }
+
+    #[test]
+    fn test_unalias_all() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+        env.aliases.insert("la".to_string(), "ls -a".to_string());
+
+        builtin_unalias(&["-a"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert!(env.aliases.is_empty());
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_unalias_unknown_name() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+        let mut err_buf = Vec::new();
+
+        // The known alias still goes
+        builtin_unalias(&["nope", "ll"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "unalias: nope: not found\n");
+        assert_eq!(env.last_status, 1);
+        assert!(env.aliases.is_empty());
+    }
+
+    #[test]
+    fn test_jobs_lists_running_and_done() {
+        use crate::jobs::Job;
+        use std::process::Command;
+
+        let mut env = ShellEnv::empty();
+        let running = Command::new("sleep").arg("100").spawn().unwrap();
+        env.jobs.push(Job::new(1, "sleep 100", vec![running]));
+        // A job with no processes left is already done
+        env.jobs.push(Job::new(2, "true", Vec::new()));
+
+        let mut out = Vec::new();
+        let result = builtin_jobs(&[], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(result, ShellAction::Continue);
+
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output, "[1]-  Running  sleep 100 &\n[2]+  Done  true\n");
+
+        // The finished job is gone, the running one stays
+        assert_eq!(env.jobs.len(), 1);
+        assert_eq!(env.jobs[0].id, 1);
+
+        env.jobs[0].children[0].kill().unwrap();
+        env.jobs[0].children[0].wait().unwrap();
+    }
+
+    #[test]
+    fn test_jobs_with_empty_table_prints_nothing() {
+        let mut env = ShellEnv::empty();
+        let mut out = Vec::new();
+
+        builtin_jobs(&[], &mut env, &mut out, &mut Vec::new());
+        assert!(out.is_empty());
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_fg_waits_for_job_and_removes_it() {
+        use crate::jobs::Job;
+        use std::process::Command;
+
+        let mut env = ShellEnv::empty();
+        let child = Command::new("sh").args(["-c", "sleep 0.1; exit 3"]).spawn().unwrap();
+        env.jobs.push(Job::new(1, "sh -c 'sleep 0.1; exit 3'", vec![child]));
+
+        let mut out = Vec::new();
+        let result = builtin_fg(&["%1"], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(result, ShellAction::Continue);
+
+        assert_eq!(String::from_utf8(out).unwrap(), "sh -c 'sleep 0.1; exit 3'\n");
+        assert_eq!(env.last_status, 3);
+        assert!(env.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_fg_defaults_to_most_recent_job() {
+        use crate::jobs::Job;
+        use std::process::Command;
+
+        let mut env = ShellEnv::empty();
+        env.jobs.push(Job::new(1, "true", vec![Command::new("true").spawn().unwrap()]));
+        env.jobs.push(Job::new(2, "false", vec![Command::new("false").spawn().unwrap()]));
+
+        builtin_fg(&[], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 1);
+        assert_eq!(env.jobs.len(), 1);
+        assert_eq!(env.jobs[0].id, 1);
+
+        env.jobs[0].wait();
+    }
+
+    #[test]
+    fn test_fg_unknown_job_is_error() {
+        let mut env = ShellEnv::empty();
+        let mut err_buf = Vec::new();
+
+        builtin_fg(&["%1"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "fg: %1: no such job\n");
+        assert_eq!(env.last_status, 1);
+
+        let mut err_buf = Vec::new();
+        builtin_fg(&[], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "fg: no such job\n");
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("9"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("KILL"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("sigterm"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("NOPE"), None);
+        assert_eq!(parse_signal("99"), None);
+    }
+
+    #[test]
+    fn test_kill_job_by_spec() {
+        use crate::jobs::{reap_jobs, Job};
+        use std::process::Command;
+
+        use std::os::unix::process::CommandExt;
+
+        let mut env = ShellEnv::empty();
+        let running = Command::new("sleep").arg("100").process_group(0).spawn().unwrap();
+        env.jobs.push(Job::new(1, "sleep 100", vec![running]));
+
+        builtin_kill(&["%1"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
+
+        // Once it has gone it's reaped like any finished job
+        assert_eq!(env.jobs[0].wait(), 128 + libc::SIGTERM);
+        let mut out = Vec::new();
+        reap_jobs(&mut env.jobs, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "[1]+ Done  sleep 100\n");
+    }
+
+    #[test]
+    fn test_kill_job_signals_its_process_group() {
+        use crate::jobs::Job;
+        use std::os::unix::process::CommandExt;
+        use std::process::Command;
+
+        // Like `sleep 100 | sleep 100 &`: both processes in the first one's group
+        let first = Command::new("sleep").arg("100").process_group(0).spawn().unwrap();
+        let second = Command::new("sleep").arg("100").process_group(first.id() as i32).spawn().unwrap();
+        let mut env = ShellEnv::empty();
+        env.jobs.push(Job::new(1, "sleep 100 | sleep 100", vec![first, second]));
+
+        builtin_kill(&["-KILL", "%1"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
+        for child in &mut env.jobs[0].children {
+            assert_eq!(crate::external::exit_code(child.wait().unwrap()), 128 + libc::SIGKILL);
+        }
+    }
+
+    #[test]
+    fn test_kill_pid_with_signal() {
+        use std::process::Command;
+
+        let mut child = Command::new("sleep").arg("100").spawn().unwrap();
+        let pid = child.id().to_string();
+        let mut env = ShellEnv::empty();
+
+        builtin_kill(&["-KILL", &pid], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
+        assert_eq!(crate::external::exit_code(child.wait().unwrap()), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn test_kill_pid_numeric_and_default_signals() {
+        use std::process::Command;
+
+        let mut env = ShellEnv::empty();
+        let mut nine = Command::new("sleep").arg("100").spawn().unwrap();
+        builtin_kill(&["-9", &nine.id().to_string()], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
+        assert_eq!(crate::external::exit_code(nine.wait().unwrap()), 128 + libc::SIGKILL);
+
+        let mut plain = Command::new("sleep").arg("100").spawn().unwrap();
+        builtin_kill(&[&plain.id().to_string()], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(crate::external::exit_code(plain.wait().unwrap()), 128 + libc::SIGTERM);
+    }
+
+    #[test]
+    fn test_kill_errors() {
+        let kill_err = |args: &[&str]| {
+            let mut env = ShellEnv::empty();
+            let mut err_buf = Vec::new();
+            builtin_kill(args, &mut env, &mut Vec::new(), &mut err_buf);
+            (env.last_status, String::from_utf8(err_buf).unwrap())
+        };
+
+        assert_eq!(kill_err(&["-BOGUS", "1"]), (1, "kill: BOGUS: invalid signal specification\n".to_string()));
+        assert_eq!(kill_err(&["abc"]), (1, "kill: abc: arguments must be process or job IDs\n".to_string()));
+        assert_eq!(kill_err(&["%3"]), (1, "kill: %3: no such job\n".to_string()));
+        assert_eq!(kill_err(&[]), (2, "usage: kill [-SIGNAL] PID | %JOB...\n".to_string()));
+    }
+
+    #[test]
+    fn test_export_marks_variables() {
+        let mut env = ShellEnv::empty();
+        env.set_var("LOCAL", "1");
+
+        builtin_export(&["LOCAL", "NEW=2"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("NEW").unwrap(), "2");
+        assert_eq!(env.last_status, 0);
+
+        let mut out = Vec::new();
+        builtin_export(&[], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "export LOCAL='1'\nexport NEW='2'\n");
+    }
+
+    #[test]
+    fn test_env_skips_shell_local_vars() {
+        let mut env = ShellEnv::empty();
+        builtin_set(&["LOCAL", "1"], &mut env, &mut Vec::new(), &mut Vec::new());
+
+        let mut out = Vec::new();
+        builtin_env(&[], &mut env, &mut out, &mut Vec::new());
+        assert!(out.is_empty());
     }
 
     #[test]
-    fn test_echo_writes_to_output() {
-        let builtins = builtins();
-        let echo_fn = builtins["echo"];
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+    fn test_env_is_sorted_by_key() {
         let mut env = ShellEnv::empty();
-        let result = echo_fn(&["hello", "world"], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        let output = String::from_utf8(buf).unwrap();
-        assert_eq!(output.trim(), "hello world");
+        for name in ["ZED", "ALPHA", "MIDDLE", "BETA"] {
+            env.set_var(name, "x");
+            env.exported.insert(name.to_string());
+        }
+
+        let mut out = Vec::new();
+        builtin_env(&[], &mut env, &mut out, &mut Vec::new());
+        let text = String::from_utf8(out).unwrap();
+        let keys: Vec<&str> = text.lines().filter_map(|line| line.split_once('=')).map(|(k, _)| k).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert!(keys.contains(&"ALPHA") && keys.contains(&"ZED"));
     }
 
     #[test]
-    #[serial]
-    fn test_pwd_prints_current_directory() {
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+    fn test_source_sets_variables_in_this_shell() {
+        let dir = tempdir().unwrap();
+        let script = dir.path().join("setup.sh");
+        fs::write(&script, "# setup\nset PROJECT lsh\nalias ll='ls -la'\n").unwrap();
         let mut env = ShellEnv::empty();
-        let result = builtin_pwd(&[], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
 
-        let output = String::from_utf8(buf).unwrap();
-        let cwd = env::current_dir().unwrap();
-        assert_eq!(output.trim(), cwd.display().to_string());
+        let path = script.to_str().unwrap();
+        let action = builtin_source(&[path], &mut env, &builtins(), &mut Vec::new(), &mut Vec::new());
+        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(env.get_var("PROJECT").unwrap(), "lsh");
+        assert_eq!(env.aliases["ll"], "ls -la");
+        assert_eq!(env.last_status, 0);
     }
 
     #[test]
     #[serial]
-    fn test_cd_changes_directory_and_pwd_reflects_it() {
-        // Save original directory
-        let orig_dir = env::current_dir().unwrap();
+    fn test_source_keeps_variables_and_directory() {
+        let _guard = CwdGuard::new();
 
-        // Create a temporary directory
-        let tmp_dir = orig_dir.join("tmp_test_dir");
-        fs::create_dir_all(&tmp_dir).unwrap();
+        let dir = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let script = dir.path().join("setup.sh");
+        fs::write(&script, format!("set STAGE dev\n\n# move into the work area\ncd {}\n", target.path().display()))
+            .unwrap();
+        let mut env = ShellEnv::empty();
 
-        // cd into the new directory
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+        builtin_source(&[script.to_str().unwrap()], &mut env, &builtins(), &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.get_var("STAGE").unwrap(), "dev");
+        assert_eq!(
+            env::current_dir().unwrap().canonicalize().unwrap(),
+            target.path().canonicalize().unwrap()
+        );
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_source_propagates_exit() {
+        let dir = tempdir().unwrap();
+        let script = dir.path().join("quit.sh");
+        fs::write(&script, "set A 1\nexit\nset B 2\n").unwrap();
         let mut env = ShellEnv::empty();
-        let result = builtin_cd(&[tmp_dir.to_str().unwrap()], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
 
-        // pwd should now reflect the new directory
-        let mut pwd_buf = Vec::new();
+        let builtins = builtins();
+        let action = builtins["."].run(&[script.to_str().unwrap()], &mut env, &builtins, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(action, ShellAction::Exit(0));
+        assert!(env.get_var("A").is_some());
+        assert!(env.get_var("B").is_none());
+    }
+
+    #[test]
+    fn test_source_missing_file() {
+        let mut env = ShellEnv::empty();
         let mut err_buf = Vec::new();
+
+        builtin_source(&["nope.sh"], &mut env, &builtins(), &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "source: nope.sh: No such file or directory\n");
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_source_runs_with_the_given_table() {
+        let dir = tempdir().unwrap();
+        let script = dir.path().join("setup.sh");
+        fs::write(&script, "set A 1\nhelp\n").unwrap();
         let mut env = ShellEnv::empty();
-        builtin_pwd(&[], &mut env, &mut pwd_buf, &mut err_buf);
-        let output = String::from_utf8(pwd_buf).unwrap();
-        assert_eq!(output.trim(), tmp_dir.display().to_string());
 
-        // cd back to original directory
-        env::set_current_dir(&orig_dir).unwrap();
-        fs::remove_dir_all(&tmp_dir).unwrap();
+        // The script's commands are looked up in the table it's given
+        let mut table = BuiltinMap::new();
+        table.insert("help", builtins().remove("help").unwrap());
+        let mut out = Vec::new();
+        builtin_source(&[script.to_str().unwrap()], &mut env, &table, &mut out, &mut Vec::new());
+        assert!(env.get_var("A").is_none());
+        assert_eq!(String::from_utf8(out).unwrap(), "lsh builtins (help NAME for more):\n  help      Describe the builtins\n");
     }
 
     #[test]
-    #[serial]
-    fn test_cd_no_args_goes_to_home() {
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+    fn test_type_reports_builtins_aliases_and_programs() {
         let mut env = ShellEnv::new();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+        let mut out = Vec::new();
+
+        builtin_type(&["cd", "ll", "ls"], &mut env, &builtins(), &mut out, &mut Vec::new());
+        let output = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], "cd is a shell builtin");
+        assert_eq!(lines[1], "ll is aliased to `ls -la'");
+        assert!(lines[2].starts_with("ls is /"), "{}", lines[2]);
+        assert!(lines[2].ends_with("/ls"));
+        assert_eq!(env.last_status, 0);
+    }
 
-        // Save the original dir so we can restore it
-        let original_dir = std::env::current_dir().unwrap();
+    #[test]
+    fn test_type_uses_the_given_table() {
+        let mut env = ShellEnv::empty();
+        let mut out = Vec::new();
 
-        let home = std::env::var("HOME").unwrap_or_else(|_| "/".to_string());
-        let result = builtin_cd(&[], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
+        // Only what's in the shell's table counts as a builtin
+        builtin_type(&["cd"], &mut env, &BuiltinMap::new(), &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 1);
+        builtins()["type"].run(&["type"], &mut env, &builtins(), &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "type is a shell builtin\n");
+    }
 
-        let new_dir = std::env::current_dir().unwrap();
-        assert_eq!(new_dir, std::path::Path::new(&home));
+    #[test]
+    fn test_type_searches_shell_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        for name in ["mytool", "echo"] {
+            let path = dir.path().join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
 
-        // Clean up
-        std::env::set_current_dir(&original_dir).unwrap();
+        let mut env = ShellEnv::empty();
+        env.set_var("PATH", &format!("/nonexistent:{}", dir.path().display()));
+        let mut out = Vec::new();
 
-        let stdout = String::from_utf8(buf).unwrap();
-        let stderr = String::from_utf8(err_buf).unwrap();
+        // A builtin wins over a program of the same name
+        builtin_type(&["mytool", "echo"], &mut env, &builtins(), &mut out, &mut Vec::new());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("mytool is {}\necho is a shell builtin\n", dir.path().join("mytool").display())
+        );
+    }
 
-        assert!(stdout.is_empty());
-        assert!(stderr.is_empty());
+    #[test]
+    fn test_which_resolves_each_name() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let tool = dir.path().join("mytool");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        // Present but not executable, so not a match
+        fs::write(dir.path().join("plain"), "").unwrap();
+
+        let mut env = ShellEnv::empty();
+        env.set_var("PATH", &dir.path().display().to_string());
+
+        let mut out = Vec::new();
+        builtin_which(&["mytool"], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", tool.display()));
+        assert_eq!(env.last_status, 0);
+
+        let mut out = Vec::new();
+        builtin_which(&["plain", "mytool", "nonsense"], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", tool.display()));
+        assert_eq!(env.last_status, 1);
     }
 
     #[test]
-    #[serial]
-    fn test_cd_invalid_path_prints_error() {
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+    fn test_clear_writes_escape_sequence() {
         let mut env = ShellEnv::empty();
-        let result = builtin_cd(&["/definitely/not/a/real/path"], &mut env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        let output = String::from_utf8(err_buf).unwrap();
-        assert!(output.starts_with("cd: "));
+        env.last_status = 1;
+        let mut out = Vec::new();
+
+        builtin_clear(&[], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(out, b"\x1b[2J\x1b[H");
+        assert_eq!(env.last_status, 0);
     }
 
+    #[test]
+    fn test_which_ignores_builtins() {
+        let mut env = ShellEnv::new();
 
-    /// Restores the current working directory when dropped.
-    struct CwdGuard {
-        original: PathBuf,
+        let mut out = Vec::new();
+        builtin_which(&["sh"], &mut env, &mut out, &mut Vec::new());
+        assert!(String::from_utf8(out).unwrap().trim_end().ends_with("/sh"));
+        assert_eq!(env.last_status, 0);
+
+        let mut out = Vec::new();
+        builtin_which(&["nonexistent_xyz"], &mut env, &mut out, &mut Vec::new());
+        assert!(out.is_empty());
+        assert_eq!(env.last_status, 1);
+
+        // Unlike `type`, a builtin isn't a match
+        let mut out = Vec::new();
+        builtin_which(&["pushd"], &mut env, &mut out, &mut Vec::new());
+        assert!(out.is_empty());
+        assert_eq!(env.last_status, 1);
     }
 
-    impl CwdGuard {
-        fn new() -> Self {
-            let original = env::current_dir().expect("failed to get current_dir");
-            CwdGuard { original }
-        }
+    #[test]
+    fn test_type_unknown_name() {
+        let mut env = ShellEnv::new();
+        let mut err_buf = Vec::new();
+
+        builtin_type(&["no-such-command-here"], &mut env, &builtins(), &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "type: no-such-command-here: not found\n");
+        assert_eq!(env.last_status, 1);
     }
 
-    impl Drop for CwdGuard {
-        fn drop(&mut self) {
-            // Best-effort restore; ignore errors
-            let _ = env::set_current_dir(&self.original);
-        }
+    #[test]
+    fn test_history_numbers_entries() {
+        let mut env = ShellEnv::empty();
+        env.history = vec!["ls".to_string(), "cd /tmp".to_string(), "pwd".to_string()];
+
+        let mut out = Vec::new();
+        builtin_history(&[], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "    1  ls\n    2  cd /tmp\n    3  pwd\n");
+
+        // The last N keep their original numbers
+        let mut out = Vec::new();
+        builtin_history(&["2"], &mut env, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "    2  cd /tmp\n    3  pwd\n");
     }
 
     #[test]
-    #[serial]
-    fn test_cd_dash_goes_to_oldpwd() {
-        // Ensure we restore cwd even if the test panics
-        let _guard = CwdGuard::new();
+    fn test_history_clear() {
+        let mut env = ShellEnv::empty();
+        env.history = vec!["ls".to_string(), "pwd".to_string()];
 
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
-        let mut shell_env = ShellEnv::empty();
+        builtin_history(&["-c"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert!(env.history.is_empty());
 
-        // Two temporary directories to toggle between
-        let dir1 = tempdir().unwrap();
-        let dir2 = tempdir().unwrap();
-        let path1 = dir1.path().to_path_buf();
-        let path2 = dir2.path().to_path_buf();
+        let mut out = Vec::new();
+        builtin_history(&[], &mut env, &mut out, &mut Vec::new());
+        assert!(out.is_empty());
+    }
 
-        // Step 1: cd into dir1
-        let result = builtin_cd(&[path1.to_str().unwrap()], &mut shell_env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        assert_eq!(
-            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
-            std::fs::canonicalize(&path1).unwrap()
-        );
+    #[test]
+    fn test_history_bad_count() {
+        let mut env = ShellEnv::empty();
+        let mut err_buf = Vec::new();
 
-        // Step 2: cd into dir2
-        let result = builtin_cd(&[path2.to_str().unwrap()], &mut shell_env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        assert_eq!(
-            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
-            std::fs::canonicalize(&path2).unwrap()
-        );
+        builtin_history(&["lots"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "history: lots: numeric argument required\n");
+        assert_eq!(env.last_status, 2);
+    }
 
-        // Step 3: cd -
-        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        assert_eq!(
-            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
-            std::fs::canonicalize(&path1).unwrap()
-        );
+    #[test]
+    fn test_read_sets_variable() {
+        let mut env = ShellEnv::empty();
+        let mut input = Cursor::new("hello world\nsecond\n");
 
-        // Step 4: cd - again → back to dir2
-        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
-        assert_eq!(
-            std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap(),
-            std::fs::canonicalize(&path2).unwrap()
-        );
+        read_line_into(&["LINE"], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("LINE").unwrap(), "hello world");
+        assert_eq!(env.last_status, 0);
 
-        // No unexpected stderr
-        let stderr = String::from_utf8(err_buf).unwrap();
-        assert!(stderr.is_empty());
+        // The next read picks up where the last left off, into REPLY by default
+        read_line_into(&[], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("REPLY").unwrap(), "second");
+    }
 
-        // _guard drops here and restores the original cwd
+    #[test]
+    fn test_read_splits_into_several_variables() {
+        let mut env = ShellEnv::empty();
+        let mut input = Cursor::new("  one   two three  four  \n");
+
+        read_line_into(&["A", "B", "C"], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("A").unwrap(), "one");
+        assert_eq!(env.get_var("B").unwrap(), "two");
+        assert_eq!(env.get_var("C").unwrap(), "three  four");
+
+        // Missing fields are set empty
+        let mut input = Cursor::new("only\n");
+        read_line_into(&["A", "B"], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("A").unwrap(), "only");
+        assert_eq!(env.get_var("B").unwrap(), "");
     }
 
     #[test]
-    #[serial]
-    fn test_cd_dash_goes_writes_to_err_old_pwd_not_set() {
-        // Ensure we restore cwd even if the test panics
-        let _guard = CwdGuard::new();
+    fn test_read_splits_on_ifs() {
+        let mut env = ShellEnv::empty();
+        env.set_var("IFS", ":");
+        let mut input = Cursor::new("root:x: 0 :0\n");
+
+        read_line_into(&["USER", "PASS", "REST"], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("USER").unwrap(), "root");
+        assert_eq!(env.get_var("PASS").unwrap(), "x");
+        assert_eq!(env.get_var("REST").unwrap(), " 0 :0");
+
+        // An empty IFS keeps the line whole
+        env.set_var("IFS", "");
+        let mut input = Cursor::new("  a b  \n");
+        read_line_into(&["A", "B"], &mut env, &mut input, &mut Vec::new());
+        assert_eq!(env.get_var("A").unwrap(), "  a b  ");
+        assert_eq!(env.get_var("B").unwrap(), "");
+    }
 
-        let mut buf = Vec::new();
+    #[test]
+    fn test_read_prompt_and_eof() {
+        let mut env = ShellEnv::empty();
         let mut err_buf = Vec::new();
-        let mut shell_env = ShellEnv::empty();
 
-        let result = builtin_cd(&["-"], &mut shell_env, &mut buf, &mut err_buf);
-        assert_eq!(result, ShellAction::Continue);
+        read_line_into(&["-p", "name: ", "NAME"], &mut env, &mut Cursor::new(""), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "name: ");
+        assert_eq!(env.get_var("NAME").unwrap(), "");
+        assert_eq!(env.last_status, 1);
+    }
 
-        // No unexpected stderr
-        let stderr = String::from_utf8(err_buf).unwrap();
-        assert_eq!(stderr.trim(), "cd: OLDPWD not set");
+    #[test]
+    fn test_true_and_false_set_status() {
+        let mut env = ShellEnv::empty();
+
+        builtin_false(&[], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 1);
+        builtin_true(&["ignored"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
     }
 
     #[test]
-    fn test_builtin_env_prints_all_vars() {
+    fn test_true_and_false_drive_and_or() {
+        // No PATH, so these can only be the builtins
         let mut env = ShellEnv::empty();
-        env.set_var("USER", "testuser");
-        env.set_var("HOME", "/tmp");
-        env.set_var("PATH", "/usr/bin");
+        let builtins = builtins();
 
-        let mut output = Cursor::new(Vec::new());
+        crate::command_processor::handle_command("false && set R no || set R yes; true && set S ok", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("R").unwrap(), "yes");
+        assert_eq!(env.get_var("S").unwrap(), "ok");
+        assert_eq!(env.last_status, 0);
+    }
 
-        let result = builtin_env(&[], &mut env, &mut output, &mut std::io::sink());
-        assert!(matches!(result, ShellAction::Continue));
+    /// Run `test` with these arguments and return the status
+    fn test_status(args: &[&str]) -> i32 {
+        let mut env = ShellEnv::empty();
+        builtin_test(args, &mut env, &mut Vec::new(), &mut Vec::new());
+        env.last_status
+    }
 
-        let output_str = String::from_utf8(output.into_inner()).unwrap();
+    #[test]
+    fn test_test_file_operators() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("file.txt");
+        fs::write(&file, "x").unwrap();
+        let (file, dir_path) = (file.to_str().unwrap(), dir.path().to_str().unwrap());
+
+        assert_eq!(test_status(&["-e", file]), 0);
+        assert_eq!(test_status(&["-e", dir_path]), 0);
+        assert_eq!(test_status(&["-e", "/nonexistent"]), 1);
+        assert_eq!(test_status(&["-f", file]), 0);
+        assert_eq!(test_status(&["-f", dir_path]), 1);
+        assert_eq!(test_status(&["-d", dir_path]), 0);
+        assert_eq!(test_status(&["-d", file]), 1);
+    }
 
-        // Each env var should appear as key=value followed by newline
-        assert!(output_str.contains("USER=testuser"));
-        assert!(output_str.contains("HOME=/tmp"));
-        assert!(output_str.contains("PATH=/usr/bin"));
+    #[test]
+    fn test_test_string_operators() {
+        assert_eq!(test_status(&["-z", ""]), 0);
+        assert_eq!(test_status(&["-z", "x"]), 1);
+        assert_eq!(test_status(&["-n", "x"]), 0);
+        assert_eq!(test_status(&["-n", ""]), 1);
+        assert_eq!(test_status(&["abc", "=", "abc"]), 0);
+        assert_eq!(test_status(&["abc", "=", "abd"]), 1);
+        assert_eq!(test_status(&["abc", "!=", "abd"]), 0);
+        assert_eq!(test_status(&["abc", "!=", "abc"]), 1);
+        // A single argument is true if it isn't empty
+        assert_eq!(test_status(&["word"]), 0);
+        assert_eq!(test_status(&[""]), 1);
+        assert_eq!(test_status(&[]), 1);
+    }
 
-        // Should print one per line, so 3 lines total
-        let lines: Vec<&str> = output_str.lines().collect();
-        assert_eq!(lines.len(), 3);
+    #[test]
+    fn test_test_integer_operators() {
+        assert_eq!(test_status(&["3", "-eq", "3"]), 0);
+        assert_eq!(test_status(&["3", "-eq", "4"]), 1);
+        assert_eq!(test_status(&["3", "-ne", "4"]), 0);
+        assert_eq!(test_status(&["3", "-ne", "3"]), 1);
+        assert_eq!(test_status(&["-2", "-lt", "1"]), 0);
+        assert_eq!(test_status(&["2", "-lt", "1"]), 1);
+        assert_eq!(test_status(&["10", "-gt", "9"]), 0);
+        assert_eq!(test_status(&["9", "-gt", "10"]), 1);
+        assert_eq!(test_status(&["5", "-le", "5"]), 0);
+        assert_eq!(test_status(&["5", "-ge", "6"]), 1);
     }
 
     #[test]
-    fn test_builtin_set_sets_the_env() {
+    fn test_test_negation() {
+        assert_eq!(test_status(&["!", "-z", "x"]), 0);
+        assert_eq!(test_status(&["!", "a", "=", "a"]), 1);
+        assert_eq!(test_status(&["!", ""]), 0);
+        // `!` as an operand of a comparison isn't a negation
+        assert_eq!(test_status(&["!", "=", "!"]), 0);
+    }
 
+    #[test]
+    fn test_test_errors() {
         let mut env = ShellEnv::empty();
-        let mut buf = Vec::new();
         let mut err_buf = Vec::new();
 
-        let result = builtin_set(&["hello", "world"], &mut env, &mut buf, &mut err_buf);
-        assert!(matches!(result, ShellAction::Continue));
+        builtin_test(&["x", "-eq", "1"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "test: x: integer expression expected\n");
+        assert_eq!(env.last_status, 2);
 
-        assert_eq!(env.get_var("hello").unwrap(), "world");
+        assert_eq!(test_status(&["-q", "x"]), 2);
+        assert_eq!(test_status(&["a", "-q", "b"]), 2);
+        assert_eq!(test_status(&["a", "=", "b", "c"]), 2);
     }
 
     #[test]
-    fn test_builtin_set_without_args_raises_error() {
-
+    fn test_bracket_form() {
+        let builtins = builtins();
+        let bracket = |args: &[&str], env: &mut ShellEnv, out: &mut dyn Write, err: &mut dyn Write| {
+            builtins["["].run(args, env, &builtins, out, err)
+        };
         let mut env = ShellEnv::empty();
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
 
-        let result = builtin_set(&[], &mut env, &mut buf, &mut err_buf);
-        assert!(matches!(result, ShellAction::Continue));
+        bracket(&["a", "=", "a", "]"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 0);
 
-        // No unexpected stderr
-        let stderr = String::from_utf8(err_buf).unwrap();
-        assert_eq!(stderr.trim(), "usage: set VAR VALUE");
+        bracket(&["1", "-gt", "2", "]"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 1);
+
+        bracket(&["]"], &mut env, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(env.last_status, 1);
     }
 
     #[test]
-    fn test_builtin_unset_with_args_unsets_the_var() {
-
+    fn test_bracket_missing_close() {
         let mut env = ShellEnv::empty();
-        let mut buf = Vec::new();
         let mut err_buf = Vec::new();
 
-        env.set_var("hello", "world");
-
-        let result = builtin_unset(&["hello"], &mut env, &mut buf, &mut err_buf);
-        assert!(matches!(result, ShellAction::Continue));
+        builtin_bracket(&["a", "=", "a"], &mut env, &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "[: missing `]'\n");
+        assert_eq!(env.last_status, 2);
+    }
 
-        assert_eq!(env.get_var("hello"), None);
+    #[test]
+    fn test_help_lists_builtins() {
+        let mut env = ShellEnv::empty();
+        let mut out = Vec::new();
+
+        builtin_help(&[], &mut env, &builtins(), &mut out, &mut Vec::new());
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.lines().any(|line| line.starts_with("  cd ") && line.contains("Change directory")));
+        assert!(output.lines().any(|line| line.starts_with("  echo ")));
+        // One line per builtin, after the heading
+        assert_eq!(output.lines().count(), builtins().len() + 1);
     }
 
     #[test]
-    fn test_builtin_unset_without_args_raises_error() {
+    fn test_every_builtin_has_help() {
+        for (name, builtin) in builtins() {
+            assert!(builtin.usage.starts_with(name), "usage for {} is {:?}", name, builtin.usage);
+            assert!(!builtin.summary.is_empty(), "{} has no summary", name);
+        }
+    }
 
+    #[test]
+    fn test_help_for_one_builtin() {
         let mut env = ShellEnv::empty();
-        let mut buf = Vec::new();
-        let mut err_buf = Vec::new();
+        let mut out = Vec::new();
 
-        let result = builtin_unset(&[], &mut env, &mut buf, &mut err_buf);
-        assert!(matches!(result, ShellAction::Continue));
+        builtin_help(&["cd"], &mut env, &builtins(), &mut out, &mut Vec::new());
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.starts_with("cd [DIR | -]\n"));
+        assert_eq!(env.last_status, 0);
 
-        // No unexpected stderr
-        let stderr = String::from_utf8(err_buf).unwrap();
-        assert_eq!(stderr.trim(), "usage: unset VAR");
+        let mut err_buf = Vec::new();
+        builtin_help(&["nope"], &mut env, &builtins(), &mut Vec::new(), &mut err_buf);
+        assert_eq!(String::from_utf8(err_buf).unwrap(), "help: no help topics match `nope'\n");
+        assert_eq!(env.last_status, 1);
+
+        // Topics come from the table it's given
+        let mut table = BuiltinMap::new();
+        table.insert("only", Builtin { func: BuiltinFunc::Plain(builtin_true), summary: "Just this", usage: "only" });
+        let mut out = Vec::new();
+        builtin_help(&[], &mut env, &table, &mut out, &mut Vec::new());
+        assert_eq!(String::from_utf8(out).unwrap(), "lsh builtins (help NAME for more):\n  only      Just this\n");
     }
 }
@@ -0,0 +1,114 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::environment::ShellEnv;
+
+/// ANSI code for red text, for errors
+pub const RED: &str = "\x1b[31m";
+
+/// Turns the colour back off
+const RESET: &str = "\x1b[0m";
+
+/// Should the shell colour a stream? Only when it goes to a terminal, and
+/// not if NO_COLOR is set to anything (see no-color.org).
+pub fn use_color(is_tty: bool, no_color: Option<&str>) -> bool {
+    is_tty && no_color.is_none_or(str::is_empty)
+}
+
+/// Wrap `text` in the codes for `color`, or leave it plain if colour is off
+pub fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Remove `ESC [ ... letter` sequences, for when colour is off
+pub fn strip_colors(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.as_str().starts_with('[') {
+            // Parameters and intermediates run up to the final letter
+            chars.by_ref().skip(1).find(|c| c.is_ascii_alphabetic());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Print one of the shell's own `lsh: ...` errors, in red if colour is on
+pub fn print_error(env: &ShellEnv, message: impl fmt::Display) {
+    eprintln!("{}", paint(&format!("lsh: {}", message), RED, env.error_color));
+}
+
+/// A writer that shows everything written through it in one colour, so a
+/// builtin's errors can be red without it knowing
+pub struct ColorWriter<W: Write> {
+    inner: W,
+    color: &'static str,
+}
+
+impl<W: Write> ColorWriter<W> {
+    pub fn new(inner: W, color: &'static str) -> Self {
+        ColorWriter { inner, color }
+    }
+}
+
+impl<W: Write> Write for ColorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Keep line ends outside the colour, so the reset isn't left
+        // waiting on the next line
+        let text = buf.strip_suffix(b"\n").unwrap_or(buf);
+        if !text.is_empty() {
+            self.inner.write_all(self.color.as_bytes())?;
+            self.inner.write_all(text)?;
+            self.inner.write_all(RESET.as_bytes())?;
+        }
+        if text.len() < buf.len() {
+            self.inner.write_all(b"\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_color() {
+        assert!(use_color(true, None));
+        assert!(use_color(true, Some("")));
+        assert!(!use_color(true, Some("1")));
+        assert!(!use_color(false, None));
+    }
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint("oops", RED, true), "\x1b[31moops\x1b[0m");
+        assert_eq!(paint("oops", RED, false), "oops");
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        assert_eq!(strip_colors("\x1b[1;32mme\x1b[0m:~$ "), "me:~$ ");
+        assert_eq!(strip_colors("plain \x1b"), "plain \x1b");
+    }
+
+    #[test]
+    fn test_color_writer() {
+        let mut out = Vec::new();
+        writeln!(ColorWriter::new(&mut out, RED), "cd: {}: no such file", 7).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("\x1b[31m"));
+        assert!(text.ends_with("\x1b[0m\n"));
+        assert_eq!(strip_colors(&text), "cd: 7: no such file\n");
+    }
+}
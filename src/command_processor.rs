@@ -1,105 +1,3814 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::iter::Peekable;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::Chars;
+
+use crate::arith;
+use crate::color::{ColorWriter, RED};
 use crate::environment::ShellEnv;
-use crate::builtins::{BuiltinMap, ShellAction};
-use crate::external::run_external;
+use crate::builtins::{read_line_into, source_file, BuiltinMap, ShellAction};
+use crate::external::{pipe_from, run_pipeline_io, spawn_pipeline, Redirections, Stage};
+use crate::jobs::{next_job_id, Job};
+use crate::shell_io::ShellIo;
+use crate::signals::{clear_interrupt, interrupted};
+
+/// A single token produced by the tokenizer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// A word, with any quotes still in place so that later passes
+    /// know which parts were quoted
+    Word(String),
+    /// The `|` pipeline operator
+    Pipe,
+    /// The `;` command separator
+    Semi,
+    /// The `&&` operator: run the next pipeline only on success
+    And,
+    /// The `||` operator: run the next pipeline only on failure
+    Or,
+    /// A single `&`: run the preceding command in the background
+    Amp,
+    /// An unquoted line break, which ends a command like `;` does
+    Newline,
+    /// A redirection operator such as `>`; the next word is its target
+    Redirect(RedirectKind),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Pipe => write!(f, "|"),
+            Token::Semi => write!(f, ";"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Amp => write!(f, "&"),
+            Token::Newline => write!(f, "newline"),
+            Token::Redirect(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+/// The kinds of redirection we understand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    /// `>` truncates the target and writes stdout to it
+    Out,
+    /// `>>` appends stdout to the target
+    Append,
+    /// `2>` truncates the target and writes stderr to it
+    Err,
+    /// `2>>` appends stderr to the target
+    ErrAppend,
+    /// `<<` feeds the target, a here-document body, to stdin. Variables
+    /// and `$(...)` in the body are expanded unless the delimiter was quoted.
+    HereDoc { expand: bool },
+}
+
+impl fmt::Display for RedirectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedirectKind::Out => write!(f, ">"),
+            RedirectKind::Append => write!(f, ">>"),
+            RedirectKind::Err => write!(f, "2>"),
+            RedirectKind::ErrAppend => write!(f, "2>>"),
+            RedirectKind::HereDoc { .. } => write!(f, "<<"),
+        }
+    }
+}
+
+/// A redirection attached to a command, with its (unexpanded) target word
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub kind: RedirectKind,
+    pub target: String,
+}
+
+/// One command of a pipeline: its words plus any redirections
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimpleCommand {
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = self.words.clone();
+        for redirect in &self.redirects {
+            parts.push(format!("{} {}", redirect.kind, redirect.target));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// One stage of a pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(Box<CompoundCommand>),
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(command) => write!(f, "{}", command),
+            Command::Compound(command) => write!(f, "{}", command),
+        }
+    }
+}
+
+/// A command built out of other commands
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    /// `if COND; then BODY; [elif COND; then BODY;]... [else BODY;] fi`
+    If {
+        /// Each condition with the body to run if it succeeds, in order
+        branches: Vec<(Vec<AndOrList>, Vec<AndOrList>)>,
+        else_body: Option<Vec<AndOrList>>,
+    },
+    /// `while COND; do BODY; done`, or `until` to loop while COND fails
+    While {
+        until: bool,
+        condition: Vec<AndOrList>,
+        body: Vec<AndOrList>,
+    },
+    /// `for VAR [in WORD...]; do BODY; done`
+    For {
+        var: String,
+        /// The unexpanded words to loop over; None means the positional
+        /// parameters
+        words: Option<Vec<String>>,
+        body: Vec<AndOrList>,
+    },
+}
+
+impl fmt::Display for CompoundCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompoundCommand::If { branches, else_body } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{} {}; then {}; ", keyword, list_text(condition), list_text(body))?;
+                }
+                if let Some(body) = else_body {
+                    write!(f, "else {}; ", list_text(body))?;
+                }
+                write!(f, "fi")
+            }
+            CompoundCommand::While { until, condition, body } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{} {}; do {}; done", keyword, list_text(condition), list_text(body))
+            }
+            CompoundCommand::For { var, words, body } => {
+                write!(f, "for {}", var)?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do {}; done", list_text(body))
+            }
+        }
+    }
+}
+
+/// The commands of a pipeline, in order
+pub type Pipeline = Vec<Command>;
+
+/// Rebuild the text of a pipeline, e.g. for the job table
+fn pipeline_text(pipeline: &Pipeline) -> String {
+    pipeline.iter().map(|command| command.to_string()).collect::<Vec<_>>().join(" | ")
+}
+
+/// Rebuild the text of a list of commands, separated by `;`
+fn list_text(list: &[AndOrList]) -> String {
+    list.iter().map(|and_or| and_or.to_string()).collect::<Vec<_>>().join("; ")
+}
+
+/// How a pipeline is joined to the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    /// `&&`: run only if the previous pipeline succeeded
+    And,
+    /// `||`: run only if the previous pipeline failed
+    Or,
+}
+
+/// Pipelines joined by `&&` and `||`, evaluated left to right
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOrList {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    /// Set when the list ended with `&`
+    pub background: bool,
+}
+
+impl fmt::Display for AndOrList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", pipeline_text(&self.first))?;
+        for (connector, pipeline) in &self.rest {
+            let operator = match connector {
+                Connector::And => "&&",
+                Connector::Or => "||",
+            };
+            write!(f, " {} {}", operator, pipeline_text(pipeline))?;
+        }
+        if self.background {
+            write!(f, " &")?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors found while tokenizing or parsing a command line
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A quote was opened but never closed
+    UnterminatedQuote(char),
+    /// An operator appeared where a command was expected
+    UnexpectedToken(String),
+    /// The input ended inside a compound command that needed this keyword
+    MissingKeyword(&'static str),
+    /// The input ended before the line closing a here-document
+    UnterminatedHereDoc(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote) => {
+                write!(f, "unexpected end of input while looking for matching `{}'", quote)
+            }
+            ParseError::UnexpectedToken(token) => {
+                write!(f, "parse error near `{}'", token)
+            }
+            ParseError::MissingKeyword(keyword) => {
+                write!(f, "unexpected end of input while looking for `{}'", keyword)
+            }
+            ParseError::UnterminatedHereDoc(delimiter) => {
+                write!(f, "unexpected end of input while looking for here-document delimiter `{}'", delimiter)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Run a line of input with the standard streams in `io`
+pub fn handle_command(input: &str, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> ShellAction {
+    // Only a Ctrl-C from now on should stop this command's loops
+    clear_interrupt();
+    execute_input(input, env, builtins, io)
+}
+
+/// Run a line of input and return its output, minus trailing newlines,
+/// for a `$(...)` command substitution. An `exit` inside only ends the
+/// substitution, and the shell goes on to exit with its own status.
+/// Input and errors go where they were going anyway.
+fn capture_output(input: &str, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> String {
+    let outer = io.stdout.replace(Vec::new());
+    execute_input(input, env, builtins, io);
+    let captured = std::mem::replace(&mut io.stdout, outer);
+
+    let mut output = String::from_utf8_lossy(&captured.unwrap_or_default()).into_owned();
+    output.truncate(output.trim_end_matches('\n').len());
+    output
+}
+
+/// Parse and run a line of input
+fn execute_input(
+    input: &str,
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> ShellAction {
+    let parsed = tokenize(input)
+        .and_then(|tokens| expand_aliases(tokens, env))
+        .and_then(parse_sequence);
+    let sequence = match parsed {
+        Ok(sequence) => sequence,
+        Err(e) => {
+            io.error(env, e);
+            env.last_status = 2;
+            return ShellAction::Continue;
+        }
+    };
+
+    execute_list(&sequence, env, builtins, io)
+}
+
+/// Run each and-or list in turn, whatever its status; only exit or
+/// Ctrl-C stops us
+fn execute_list(
+    sequence: &[AndOrList],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> ShellAction {
+    for list in sequence {
+        if let exit @ ShellAction::Exit(_) = execute_and_or(list, env, builtins, io) {
+            return exit;
+        }
+        if was_interrupted(env) {
+            break;
+        }
+    }
+
+    ShellAction::Continue
+}
+
+/// Whether a line typed at the prompt belongs in the history. Blank lines
+/// never do. HISTCONTROL, a `:`-separated list, can say `ignoredups` to
+/// skip a repeat of the previous entry, `ignorespace` to skip lines that
+/// start with a space, or `ignoreboth`; unset it means `ignoredups`.
+pub fn should_record_history(line: &str, prev: Option<&str>, env: &ShellEnv) -> bool {
+    if line.trim().is_empty() {
+        return false;
+    }
+
+    let control = env.get_var("HISTCONTROL").map_or("ignoredups", |value| value.as_str());
+    let mut ignore_dups = false;
+    let mut ignore_space = false;
+    for mode in control.split(':') {
+        match mode {
+            "ignoredups" => ignore_dups = true,
+            "ignorespace" => ignore_space = true,
+            "ignoreboth" => (ignore_dups, ignore_space) = (true, true),
+            _ => {}
+        }
+    }
+
+    !(ignore_dups && prev == Some(line) || ignore_space && line.starts_with(' '))
+}
+
+/// Expand history references in a line typed at the prompt: `!!` for the
+/// previous command, `!n` for entry n, `!-n` for the nth most recent and
+/// `!text` for the latest command starting with `text`. Nothing inside
+/// single quotes or after a backslash is expanded. Returns None when the
+/// line has no references, and an error naming any that can't be found.
+pub fn expand_history(input: &str, history: &[String]) -> Result<Option<String>, String> {
+    let mut result = String::new();
+    let mut expanded = false;
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // A `'` inside double quotes is just a character
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\\' if !in_single_quotes => {
+                result.push(c);
+                result.extend(chars.next());
+                continue;
+            }
+            '!' if !in_single_quotes => {
+                // A `!` before a space, `=`, `(` or `"` (or at the end) is just a `!`
+                let event: String = match chars.peek() {
+                    Some('!') => {
+                        chars.next();
+                        "!".to_string()
+                    }
+                    Some(&next) if !next.is_whitespace() && !matches!(next, '=' | '(' | '"') => {
+                        let mut event = String::new();
+                        while let Some(&next) = chars.peek() {
+                            let ends_event = matches!(next, ';' | '|' | '&' | '>' | '\'' | '"');
+                            if next.is_whitespace() || ends_event {
+                                break;
+                            }
+                            event.push(next);
+                            chars.next();
+                        }
+                        event
+                    }
+                    _ => {
+                        result.push(c);
+                        continue;
+                    }
+                };
+                let entry = find_history_event(&event, history)
+                    .ok_or_else(|| format!("!{}: event not found", event))?;
+                result.push_str(entry);
+                expanded = true;
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+    }
+
+    Ok(expanded.then_some(result))
+}
+
+/// Look up one history reference, without its leading `!`
+fn find_history_event<'a>(event: &str, history: &'a [String]) -> Option<&'a String> {
+    if event == "!" {
+        return history.last();
+    }
+    if let Some(back) = event.strip_prefix('-') {
+        let back: usize = back.parse().ok()?;
+        return history.len().checked_sub(back).and_then(|index| history.get(index));
+    }
+    match event.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).and_then(|index| history.get(index)),
+        Err(_) => history.iter().rev().find(|entry| entry.starts_with(event)),
+    }
+}
+
+/// Is this input unfinished, so the rest of the command is on the next
+/// line? That's the case when a quote or `$(` is left open, a
+/// here-document hasn't reached its delimiter, a compound command such as
+/// `if` hasn't been closed, the input ends in `|`, `&&` or `||` after a
+/// command, or it ends in a backslash that isn't itself escaped.
+pub fn needs_more_input(input: &str) -> bool {
+    let mut tokens = match tokenize(input) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            return matches!(err, ParseError::UnterminatedQuote(_) | ParseError::UnterminatedHereDoc(_));
+        }
+    };
+
+    // A trailing operator is waiting for the command that goes after it
+    let trailing_operator = matches!(tokens.last(), Some(Token::Pipe | Token::And | Token::Or));
+    if trailing_operator {
+        tokens.pop();
+    }
+    let after_command = matches!(tokens.last(), Some(Token::Word(_)));
+
+    match parse_sequence(tokens) {
+        Err(ParseError::MissingKeyword(_)) => true,
+        Ok(_) if trailing_operator => after_command,
+        // With every quote closed, an odd run of trailing backslashes
+        // means the last one escapes the newline
+        _ => input.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1,
+    }
+}
+
+/// Read a whole command starting from `first`, calling `next_line` for
+/// more lines while the input is unfinished. Lines are joined with
+/// newlines, which the tokenizer drops after a backslash and keeps inside
+/// quotes. Returns None if `next_line` gives up (say on Ctrl-C) part way.
+pub fn read_complete_command(first: String, mut next_line: impl FnMut() -> Option<String>) -> Option<String> {
+    let mut input = first;
+    while needs_more_input(&input) {
+        let line = next_line()?;
+        input.push('\n');
+        input.push_str(&line);
+    }
+    Some(input)
+}
+
+/// Run a script one command at a time, skipping blank lines and `#` comments.
+/// Stops early if a line asks the shell to exit, or on Ctrl-C.
+pub fn run_lines(contents: &str, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> ShellAction {
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // A command can carry on over several lines, as at the prompt
+        let mut command = line.to_string();
+        while needs_more_input(&command) {
+            let Some(more) = lines.next() else { break };
+            command.push('\n');
+            command.push_str(more);
+        }
+
+        if let exit @ ShellAction::Exit(_) = handle_command(&command, env, builtins, io) {
+            return exit;
+        }
+        if was_interrupted(env) {
+            break;
+        }
+    }
+
+    ShellAction::Continue
+}
+
+/// Read a file and run its lines, as for `~/.lshrc` and `source`
+pub fn run_file(path: &Path, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> io::Result<ShellAction> {
+    let contents = fs::read_to_string(path)?;
+    Ok(run_lines(&contents, env, builtins, io))
+}
+
+/// Run an and-or list, skipping pipelines whose connector doesn't match
+/// the status of the last one that ran
+fn execute_and_or(
+    list: &AndOrList,
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> ShellAction {
+    if list.background {
+        // Without subshells we can only put a single pipeline in the background
+        if !list.rest.is_empty() {
+            io.error(env, "only a single pipeline can run in the background");
+            env.last_status = 1;
+            return ShellAction::Continue;
+        }
+        return execute_pipeline(&list.first, env, builtins, true, io);
+    }
+
+    if let exit @ ShellAction::Exit(_) = execute_pipeline(&list.first, env, builtins, false, io) {
+        return exit;
+    }
+
+    for (connector, pipeline) in &list.rest {
+        let should_run = match connector {
+            Connector::And => env.last_status == 0,
+            Connector::Or => env.last_status != 0,
+        };
+        if should_run && let exit @ ShellAction::Exit(_) = execute_pipeline(pipeline, env, builtins, false, io) {
+            return exit;
+        }
+    }
+
+    ShellAction::Continue
+}
+
+/// Expand and run the commands of a pipeline. Builtins run in the shell
+/// itself, a stage at a time if piped; a pipeline of external commands is
+/// handed to the external pipeline runner, either waiting for it or
+/// recording it as a background job.
+fn execute_pipeline(
+    commands: &Pipeline,
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    background: bool,
+    io: &mut ShellIo,
+) -> ShellAction {
+    if commands.is_empty() {
+        return ShellAction::Continue;
+    }
+
+    // Compound commands run in the shell itself, so they can't (yet) be
+    // piped or put in the background
+    let mut simple_commands = Vec::new();
+    for command in commands {
+        match command {
+            Command::Simple(command) => simple_commands.push(command),
+            Command::Compound(compound) if commands.len() == 1 && !background => {
+                return execute_compound(compound, env, builtins, io);
+            }
+            Command::Compound(_) => {
+                io.error(env, "compound commands can't be piped or run in the background");
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        }
+    }
+
+    // Expand every stage and open its files up front, so nothing runs if
+    // a redirection fails
+    let mut expanded: Vec<Vec<String>> = Vec::new();
+    let mut assignments: Vec<Vec<(String, String)>> = Vec::new();
+    let mut redirections: Vec<Redirections> = Vec::new();
+    for command in simple_commands {
+        let count = command.words.iter().take_while(|word| is_assignment(word)).count();
+        let (prefix, words) = command.words.split_at(count);
+        let substituted = prefix
+            .iter()
+            .map(|word| expand_assignment(word, env, builtins, io))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|vars| Ok((vars, substitute_commands(words, env, builtins, io)?)));
+        let words = match substituted {
+            Ok((vars, words)) => {
+                assignments.push(vars);
+                words
+            }
+            Err(msg) => {
+                io.error(env, msg);
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        };
+        expanded.push(expand_args(&as_str_vec(&words), env));
+        match open_redirections(&command.redirects, env, builtins, io) {
+            Ok(opened) => redirections.push(opened),
+            Err(msg) => {
+                io.error(env, msg);
+                env.last_status = 1;
+                return ShellAction::Continue;
+            }
+        }
+    }
+
+    // A command made only of redirections just creates the files, and
+    // bare `NAME=value` words set shell variables
+    if expanded.len() == 1 && expanded[0].is_empty() {
+        for (name, value) in assignments.pop().unwrap() {
+            env.set_var(&name, &value);
+        }
+        env.last_status = 0;
+        return ShellAction::Continue;
+    }
+
+    if expanded.len() == 1 && builtins.contains_key(expanded[0][0].as_str()) {
+        let vars = assignments.pop().unwrap();
+        return run_builtin(&expanded[0], env, builtins, io, redirections.pop().unwrap(), vars);
+    }
+
+    // Builtins and empty stages can't be handed to the pipeline runner
+    let in_shell = |words: &Vec<String>| words.is_empty() || builtins.contains_key(words[0].as_str());
+    if expanded.iter().any(in_shell) {
+        return run_stages(&expanded, env, builtins, io, redirections, assignments);
+    }
+
+    let stages: Vec<Stage> = expanded
+        .iter()
+        .zip(redirections)
+        .zip(assignments)
+        .map(|((words, redirections), assignments)| Stage {
+            cmd: words[0].as_str(),
+            args: as_str_vec(&words[1..]),
+            redirections,
+            assignments,
+        })
+        .collect();
+
+    if background {
+        // Background jobs don't get to read the terminal
+        let children: Vec<_> = spawn_pipeline(stages, env, Stdio::null(), Stdio::inherit(), None, true)
+            .into_iter()
+            .flatten()
+            .collect();
+        if children.is_empty() {
+            env.last_status = 127;
+            return ShellAction::Continue;
+        }
+
+        let job = Job::new(next_job_id(&env.jobs), &pipeline_text(commands), children);
+        io.notice(format_args!("[{}] {}", job.id, job.pid));
+        env.jobs.push(job);
+        env.last_status = 0;
+    } else {
+        env.last_status = run_pipeline_io(stages, env, io);
+    }
+    ShellAction::Continue
+}
+
+/// Run a builtin in the shell itself, with `io`'s streams unless they
+/// are redirected, and with `vars` set while it runs
+fn run_builtin(
+    words: &[String],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+    redirection: Redirections,
+    vars: Vec<(String, String)>,
+) -> ShellAction {
+    let builtin = &builtins[words[0].as_str()];
+    let args = as_str_vec(&words[1..]);
+
+    // A sourced script runs in this shell, so needs its streams and
+    // builtins rather than a builtin's writers
+    if matches!(words[0].as_str(), "source" | ".") {
+        return with_temporary_vars(env, vars, |env| run_source(&args, env, builtins, io, redirection));
+    }
+
+    // Builtins write straight to the redirected files, if any
+    let mut out: Box<dyn Write + '_> = match (redirection.stdout, &mut io.stdout) {
+        (Some(file), _) => Box::new(file),
+        (None, Some(buffer)) => Box::new(buffer),
+        (None, None) => Box::new(std::io::stdout()),
+    };
+    let mut err: Box<dyn Write + '_> = match (redirection.stderr, &mut io.stderr) {
+        (Some(file), _) => Box::new(file),
+        (None, Some(buffer)) => Box::new(buffer),
+        // Errors on the terminal are red, when colour is on
+        (None, None) if env.error_color => Box::new(ColorWriter::new(std::io::stderr(), RED)),
+        (None, None) => Box::new(std::io::stderr()),
+    };
+
+    // `read` is the one builtin that reads stdin, so it takes its line
+    // from a here-document or in-memory input here
+    if words[0] == "read" {
+        let stdin = &mut io.stdin;
+        return with_temporary_vars(env, vars, |env| match (redirection.stdin, stdin) {
+            (Some(body), _) => read_line_into(&args, env, &mut BufReader::new(body), &mut err),
+            (None, Some(input)) => {
+                // Leave the rest of the input for whatever reads next
+                let mut unread = input.as_slice();
+                let action = read_line_into(&args, env, &mut unread, &mut err);
+                let used = input.len() - unread.len();
+                input.drain(..used);
+                action
+            }
+            (None, None) => builtin.run(&args, env, builtins, &mut out, &mut err),
+        });
+    }
+    with_temporary_vars(env, vars, |env| builtin.run(&args, env, builtins, &mut out, &mut err))
+}
+
+/// Run a pipeline with builtins or empty stages in it, one stage at a
+/// time. Each stage's output is collected in memory and fed to the next,
+/// and a stage with no words passes its input straight through. Returns
+/// once the last stage has run, with its status.
+fn run_stages(
+    expanded: &[Vec<String>],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+    redirections: Vec<Redirections>,
+    assignments: Vec<Vec<(String, String)>>,
+) -> ShellAction {
+    let count = expanded.len();
+    // The first stage reads our own stdin
+    let mut input = io.stdin.take();
+
+    for (i, ((words, redirection), vars)) in expanded.iter().zip(redirections).zip(assignments).enumerate() {
+        let is_last = i == count - 1;
+        let mut stage_io = ShellIo {
+            stdin: input.take(),
+            stdout: if is_last { io.stdout.take() } else { Some(Vec::new()) },
+            stderr: io.stderr.take(),
+        };
+
+        let action = if words.is_empty() {
+            let passed = stage_io.stdin.as_mut().map(std::mem::take).unwrap_or_default();
+            let _ = match (redirection.stdout, &mut stage_io.stdout) {
+                (Some(mut file), _) => file.write_all(&passed),
+                (None, Some(buffer)) => buffer.write_all(&passed),
+                (None, None) => std::io::stdout().write_all(&passed),
+            };
+            env.last_status = 0;
+            ShellAction::Continue
+        } else if builtins.contains_key(words[0].as_str()) {
+            run_builtin(words, env, builtins, &mut stage_io, redirection, vars)
+        } else {
+            let stage = Stage { cmd: &words[0], args: as_str_vec(&words[1..]), redirections: redirection, assignments: vars };
+            env.last_status = run_pipeline_io(vec![stage], env, &mut stage_io);
+            ShellAction::Continue
+        };
+
+        io.stderr = stage_io.stderr;
+        if i == 0 {
+            // Whatever the first stage didn't read is left for later commands
+            io.stdin = stage_io.stdin;
+        }
+        if is_last {
+            io.stdout = stage_io.stdout;
+        } else {
+            input = stage_io.stdout;
+        }
+        if let exit @ ShellAction::Exit(_) = action {
+            return exit;
+        }
+    }
+    ShellAction::Continue
+}
+
+/// Run `source` with the shell's streams. A redirected one is collected
+/// in memory while the script runs, and then written to its file.
+fn run_source(
+    args: &[&str],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+    redirection: Redirections,
+) -> ShellAction {
+    let outer_stdin = redirection.stdin.map(|mut body| {
+        let mut input = Vec::new();
+        let _ = body.read_to_end(&mut input);
+        io.stdin.replace(input)
+    });
+    let outer_stdout = redirection.stdout.as_ref().map(|_| io.stdout.replace(Vec::new()));
+    let outer_stderr = redirection.stderr.as_ref().map(|_| io.stderr.replace(Vec::new()));
+
+    let action = source_file(args, env, builtins, io);
+
+    if let Some(outer) = outer_stdin {
+        io.stdin = outer;
+    }
+    if let (Some(mut file), Some(outer)) = (redirection.stdout, outer_stdout) {
+        let _ = file.write_all(&std::mem::replace(&mut io.stdout, outer).unwrap_or_default());
+    }
+    if let (Some(mut file), Some(outer)) = (redirection.stderr, outer_stderr) {
+        let _ = file.write_all(&std::mem::replace(&mut io.stderr, outer).unwrap_or_default());
+    }
+    action
+}
+
+/// Has Ctrl-C been pressed during this command? Then whatever is running
+/// stops with the status of a command killed by SIGINT.
+fn was_interrupted(env: &mut ShellEnv) -> bool {
+    if !interrupted() {
+        return false;
+    }
+    env.last_status = 128 + libc::SIGINT;
+    true
+}
+
+/// Run a compound command, returning Exit if anything inside asks to
+fn execute_compound(
+    compound: &CompoundCommand,
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> ShellAction {
+    match compound {
+        CompoundCommand::If { branches, else_body } => {
+            // The first branch whose condition succeeds is the one that runs
+            for (condition, body) in branches {
+                if let exit @ ShellAction::Exit(_) = execute_list(condition, env, builtins, io) {
+                    return exit;
+                }
+                if was_interrupted(env) {
+                    return ShellAction::Continue;
+                }
+                if env.last_status == 0 {
+                    return execute_list(body, env, builtins, io);
+                }
+            }
+            match else_body {
+                Some(body) => execute_list(body, env, builtins, io),
+                None => {
+                    env.last_status = 0;
+                    ShellAction::Continue
+                }
+            }
+        }
+        CompoundCommand::While { until, condition, body } => {
+            // The loop's status is the body's last, or 0 if it never ran
+            let mut status = 0;
+            loop {
+                if let exit @ ShellAction::Exit(_) = execute_list(condition, env, builtins, io) {
+                    return exit;
+                }
+                if was_interrupted(env) {
+                    return ShellAction::Continue;
+                }
+                if (env.last_status == 0) == *until {
+                    break;
+                }
+
+                if let exit @ ShellAction::Exit(_) = execute_list(body, env, builtins, io) {
+                    return exit;
+                }
+                if was_interrupted(env) {
+                    return ShellAction::Continue;
+                }
+                status = env.last_status;
+            }
+            env.last_status = status;
+            ShellAction::Continue
+        }
+        CompoundCommand::For { var, words, body } => {
+            // The words are expanded once, up front, like a command's arguments
+            let items = match words {
+                Some(words) => match substitute_commands(words, env, builtins, io) {
+                    Ok(words) => expand_args(&as_str_vec(&words), env),
+                    Err(msg) => {
+                        io.error(env, msg);
+                        env.last_status = 1;
+                        return ShellAction::Continue;
+                    }
+                },
+                None => env.args[1..].to_vec(),
+            };
+
+            let mut status = 0;
+            for item in items {
+                env.set_var(var, &item);
+                if let exit @ ShellAction::Exit(_) = execute_list(body, env, builtins, io) {
+                    return exit;
+                }
+                if was_interrupted(env) {
+                    return ShellAction::Continue;
+                }
+                status = env.last_status;
+            }
+            env.last_status = status;
+            ShellAction::Continue
+        }
+    }
+}
+
+/// Whether a raw word is a `NAME=value` assignment
+fn is_assignment(word: &str) -> bool {
+    let Some((name, _)) = word.split_once('=') else {
+        return false;
+    };
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Expand the value of a `NAME=value` word. The value is never split
+/// into words or globbed.
+fn expand_assignment(
+    word: &str,
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> Result<(String, String), String> {
+    let (name, value) = word.split_once('=').unwrap();
+    let value = substitute_word(value, env, builtins, io)?;
+    Ok((name.to_string(), expand_word(&value, env).text))
+}
+
+/// Run `f` with the variables set and exported, then put back whatever
+/// they were before
+fn with_temporary_vars<T>(env: &mut ShellEnv, vars: Vec<(String, String)>, f: impl FnOnce(&mut ShellEnv) -> T) -> T {
+    let mut saved = Vec::new();
+    for (name, value) in vars {
+        saved.push((name.clone(), env.get_var(&name).cloned(), env.exported.contains(&name)));
+        env.set_var(&name, &value);
+        env.export_var(&name);
+    }
+
+    let result = f(env);
+
+    // Restore in reverse so a name given twice ends up as it started
+    for (name, value, exported) in saved.into_iter().rev() {
+        match value {
+            Some(value) => env.set_var(&name, &value),
+            None => env.unset_var(&name),
+        }
+        if !exported {
+            env.exported.remove(&name);
+        }
+    }
+    result
+}
+
+/// Run the `$(...)` command substitutions and `$((...))` arithmetic in a
+/// command's words, splicing in their results. Output outside double
+/// quotes is split into words on whitespace; inside them it stays part of
+/// the one word. Either way it is escaped, so it's never taken as quotes,
+/// variables or operators. Fails if an arithmetic expression does.
+fn substitute_commands(
+    words: &[String],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> Result<Vec<String>, String> {
+    let mut result = Vec::new();
+
+    for word in words {
+        if !word.contains("$(") {
+            result.push(word.clone());
+            continue;
+        }
+
+        // Re-tokenizing splits the word wherever unquoted output had spaces
+        let substituted = substitute_word(word, env, builtins, io)?;
+        match tokenize(&substituted) {
+            Ok(tokens) => result.extend(tokens.into_iter().filter_map(|token| match token {
+                Token::Word(word) => Some(word),
+                _ => None,
+            })),
+            Err(_) => result.push(substituted),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Replace each `$(...)` in a raw word with the escaped output of the
+/// command inside, and each `$((...))` with the value of the expression.
+/// Single-quoted text is left alone.
+fn substitute_word(word: &str, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> Result<String, String> {
+    let mut result = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = word.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                result.push(c);
+            }
+            (Some(q), c) if c == q => {
+                quote = None;
+                result.push(c);
+            }
+            (None | Some('"'), '\\') => {
+                result.push(c);
+                result.extend(chars.next());
+            }
+            (None | Some('"'), '$') if chars.as_str().starts_with('(') => {
+                chars.next();
+                let mut inner = String::new();
+                // The tokenizer has already checked the parentheses match
+                let _ = copy_substitution(&mut chars, &mut inner);
+                inner.pop();
+
+                // For `$((expr))` what's left is `(expr)`, which evaluates the same
+                if inner.starts_with('(') && inner.ends_with(')') {
+                    let value = arith::eval_arith(&inner, env).map_err(|e| e.to_string())?;
+                    result.push_str(&value.to_string());
+                } else {
+                    let output = capture_output(&inner, env, builtins, io);
+                    result.push_str(&escape_output(&output, quote.is_some(), env.ifs()));
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Escape command output so expansion gives it back literally. Inside
+/// double quotes only `\`, `"` and `$` need it; outside, characters in
+/// `ifs` become plain spaces and everything else is escaped, so the
+/// separators alone split words. Runs of separators count as one.
+fn escape_output(output: &str, in_double_quotes: bool, ifs: &str) -> String {
+    let mut escaped = String::new();
+    for c in output.chars() {
+        if in_double_quotes {
+            if matches!(c, '\\' | '"' | '$') {
+                escaped.push('\\');
+            }
+        } else if ifs.contains(c) {
+            escaped.push(' ');
+            continue;
+        } else if c == '\n' {
+            // A backslash-newline would be taken as a line continuation
+            escaped.push_str("'\n'");
+            continue;
+        } else {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Open the files named by a command's redirections, and the pipes that
+/// feed here-documents to stdin
+fn open_redirections(
+    redirects: &[Redirect],
+    env: &mut ShellEnv,
+    builtins: &BuiltinMap,
+    io: &mut ShellIo,
+) -> Result<Redirections, String> {
+    let mut opened = Redirections::default();
+
+    for redirect in redirects {
+        if let RedirectKind::HereDoc { expand } = redirect.kind {
+            let body = if expand {
+                expand_heredoc(&redirect.target, env, builtins, io)?
+            } else {
+                redirect.target.clone()
+            };
+            opened.stdin = Some(pipe_from(body.into_bytes()).map_err(|e| format!("here-document: {}", e))?);
+            continue;
+        }
+
+        let target = expand_word(&redirect.target, env).text;
+        let file = open_target(&target, redirect.kind)
+            .map_err(|e| format!("{}: {}", target, e))?;
+        match redirect.kind {
+            RedirectKind::Out | RedirectKind::Append => opened.stdout = Some(file),
+            RedirectKind::Err | RedirectKind::ErrAppend => opened.stderr = Some(file),
+            RedirectKind::HereDoc { .. } => unreachable!(),
+        }
+    }
+
+    Ok(opened)
+}
+
+/// Expand `$NAME`, `$(...)` and `$((...))` in a here-document body. The
+/// body is treated as if it were in double quotes, except that a `"` is
+/// just a character, so we escape those and reuse the word expansion.
+fn expand_heredoc(body: &str, env: &mut ShellEnv, builtins: &BuiltinMap, io: &mut ShellIo) -> Result<String, String> {
+    let mut quoted = String::from("\"");
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // A backslash before `"` stays, so it needs escaping itself
+            '\\' if chars.peek() == Some(&'"') => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    let substituted = substitute_word(&quoted, env, builtins, io)?;
+    Ok(expand_word(&substituted, env).text)
+}
+
+/// Open a redirection target in the mode its operator asks for
+fn open_target(path: &str, kind: RedirectKind) -> std::io::Result<File> {
+    match kind {
+        RedirectKind::Out | RedirectKind::Err => File::create(path),
+        RedirectKind::Append | RedirectKind::ErrAppend => {
+            OpenOptions::new().create(true).append(true).open(path)
+        }
+        RedirectKind::HereDoc { .. } => unreachable!("here-documents aren't files"),
+    }
+}
+
+/// Break a command line into words and operators. Whitespace separates
+/// words unless it is inside single or double quotes.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = input.chars();
+    // Here-documents whose bodies start on the next line
+    let mut heredocs = Vec::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // A backslash-newline joins two lines into one
+                Some('\n') => {}
+                // Keep the escape and the character it protects together
+                Some(next) => {
+                    word.push(c);
+                    word.push(next);
+                }
+                None => word.push(c),
+            },
+            '\'' | '"' => copy_quoted(c, &mut chars, &mut word)?,
+            // A command substitution is part of the word, spaces and all
+            '$' if chars.as_str().starts_with('(') => {
+                word.push_str("$(");
+                chars.next();
+                copy_substitution(&mut chars, &mut word)?;
+            }
+            '|' => {
+                push_word(&mut tokens, &mut word);
+                if chars.clone().next() == Some('|') {
+                    chars.next();
+                    tokens.push(Token::Or);
+                } else {
+                    tokens.push(Token::Pipe);
+                }
+            }
+            '&' => {
+                push_word(&mut tokens, &mut word);
+                if chars.clone().next() == Some('&') {
+                    chars.next();
+                    tokens.push(Token::And);
+                } else {
+                    tokens.push(Token::Amp);
+                }
+            }
+            ';' => {
+                push_word(&mut tokens, &mut word);
+                tokens.push(Token::Semi);
+            }
+            '>' => {
+                // A bare `2` right before the operator selects stderr
+                let stderr = word == "2";
+                if stderr {
+                    word.clear();
+                }
+                push_word(&mut tokens, &mut word);
+
+                let append = chars.clone().next() == Some('>');
+                if append {
+                    chars.next();
+                }
+                let kind = match (stderr, append) {
+                    (false, false) => RedirectKind::Out,
+                    (false, true) => RedirectKind::Append,
+                    (true, false) => RedirectKind::Err,
+                    (true, true) => RedirectKind::ErrAppend,
+                };
+                tokens.push(Token::Redirect(kind));
+            }
+            '<' if chars.as_str().starts_with('<') => {
+                push_word(&mut tokens, &mut word);
+                chars.next();
+                // `<<-` strips leading tabs from the body and delimiter
+                let strip_tabs = chars.as_str().starts_with('-');
+                if strip_tabs {
+                    chars.next();
+                }
+                tokens.push(Token::Redirect(RedirectKind::HereDoc { expand: true }));
+                heredocs.push((tokens.len(), strip_tabs));
+            }
+            // A `#` starting a word comments out the rest of the line
+            '#' if word.is_empty() => {
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        read_heredocs(&mut tokens, &mut heredocs, &mut chars)?;
+                        tokens.push(Token::Newline);
+                        break;
+                    }
+                }
+            }
+            '\n' => {
+                push_word(&mut tokens, &mut word);
+                read_heredocs(&mut tokens, &mut heredocs, &mut chars)?;
+                tokens.push(Token::Newline);
+            }
+            c if c.is_whitespace() => push_word(&mut tokens, &mut word),
+            _ => word.push(c),
+        }
+    }
+    push_word(&mut tokens, &mut word);
+    read_heredocs(&mut tokens, &mut heredocs, &mut chars)?;
+
+    Ok(tokens)
+}
+
+/// Read the bodies of the here-documents started on the line just ended,
+/// in order, from the lines that follow. Each delimiter word after a `<<`
+/// is replaced by its body; a quoted delimiter turns off expansion.
+fn read_heredocs(
+    tokens: &mut [Token],
+    heredocs: &mut Vec<(usize, bool)>,
+    chars: &mut Chars,
+) -> Result<(), ParseError> {
+    for (index, strip_tabs) in heredocs.drain(..) {
+        // Without a delimiter word the parser reports the stray `<<`
+        let Some(Token::Word(word)) = tokens.get(index) else {
+            continue;
+        };
+        let quoted = word.contains(['\'', '"', '\\']);
+        let delimiter = remove_quotes(word);
+
+        let rest = chars.as_str();
+        let mut body = String::new();
+        let mut consumed = 0;
+        let mut found = false;
+        for line in rest.split_inclusive('\n') {
+            consumed += line.len();
+            let line = if strip_tabs { line.trim_start_matches('\t') } else { line };
+            if line.strip_suffix('\n').unwrap_or(line) == delimiter {
+                found = true;
+                break;
+            }
+            body.push_str(line);
+        }
+        if !found {
+            return Err(ParseError::UnterminatedHereDoc(delimiter));
+        }
+
+        *chars = rest[consumed..].chars();
+        tokens[index - 1] = Token::Redirect(RedirectKind::HereDoc { expand: !quoted });
+        tokens[index] = Token::Word(body);
+    }
+    Ok(())
+}
+
+/// A word with its quotes and backslashes taken out, but nothing expanded
+fn remove_quotes(word: &str) -> String {
+    let mut result = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = word.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '\\') => result.extend(chars.next()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Copy a quoted section, starting just after the opening `quote`,
+/// through to the closing quote. In double quotes a backslash can hide
+/// the closing quote and `$(...)` can hold quotes of its own.
+fn copy_quoted(quote: char, chars: &mut Chars, word: &mut String) -> Result<(), ParseError> {
+    word.push(quote);
+    loop {
+        match chars.next() {
+            Some(c) if c == quote => {
+                word.push(c);
+                return Ok(());
+            }
+            Some('\\') if quote == '"' => {
+                word.push('\\');
+                word.extend(chars.next());
+            }
+            Some('$') if quote == '"' && chars.as_str().starts_with('(') => {
+                word.push_str("$(");
+                chars.next();
+                copy_substitution(chars, word)?;
+            }
+            Some(other) => word.push(other),
+            None => return Err(ParseError::UnterminatedQuote(quote)),
+        }
+    }
+}
+
+/// Copy the rest of a `$(...)`, starting just after the `$(`, through the
+/// matching `)`. Nested parentheses and quotes inside are skipped over.
+fn copy_substitution(chars: &mut Chars, word: &mut String) -> Result<(), ParseError> {
+    let mut depth = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => copy_quoted(c, chars, word)?,
+            '\\' => {
+                word.push(c);
+                word.extend(chars.next());
+            }
+            '(' => {
+                depth += 1;
+                word.push(c);
+            }
+            ')' => {
+                word.push(c);
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    Err(ParseError::UnterminatedQuote(')'))
+}
+
+/// Move a finished word (if any) onto the token list
+fn push_word(tokens: &mut Vec<Token>, word: &mut String) {
+    if !word.is_empty() {
+        tokens.push(Token::Word(std::mem::take(word)));
+    }
+}
+
+/// Replace the first word of each command with its alias, if it has one.
+/// The result is expanded again so aliases can build on each other, but an
+/// alias is never expanded inside itself, so `alias ls='ls -la'` (or a
+/// loop of aliases) can't recurse forever.
+fn expand_aliases(tokens: Vec<Token>, env: &ShellEnv) -> Result<Vec<Token>, ParseError> {
+    expand_aliases_except(tokens, env, &mut Vec::new())
+}
+
+/// Alias expansion that skips the aliases in `active`, which are the ones
+/// we're already in the middle of expanding
+fn expand_aliases_except(
+    tokens: Vec<Token>,
+    env: &ShellEnv,
+    active: &mut Vec<String>,
+) -> Result<Vec<Token>, ParseError> {
+    let mut expanded = Vec::new();
+    let mut command_start = true;
+    let mut after_redirect = false;
+
+    for token in tokens {
+        match token {
+            // The word after a redirection is a file name, not a command
+            Token::Word(_) if after_redirect => {
+                after_redirect = false;
+                expanded.push(token);
+            }
+            Token::Word(word) if command_start => {
+                // After a keyword like `if` or `then` comes another command
+                command_start = COMMAND_KEYWORDS.contains(&word.as_str());
+                match env.aliases.get(&word) {
+                    Some(value) if !active.contains(&word) => {
+                        active.push(word);
+                        expanded.extend(expand_aliases_except(tokenize(value)?, env, active)?);
+                        active.pop();
+                    }
+                    _ => expanded.push(Token::Word(word)),
+                }
+            }
+            Token::Word(_) => expanded.push(token),
+            Token::Pipe | Token::Semi | Token::And | Token::Or | Token::Amp | Token::Newline => {
+                command_start = true;
+                expanded.push(token);
+            }
+            Token::Redirect(_) => {
+                after_redirect = true;
+                expanded.push(token);
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Keywords that are followed by a command
+const COMMAND_KEYWORDS: [&str; 7] = ["if", "then", "elif", "else", "while", "until", "do"];
+
+/// Keywords that can only close or continue a compound command, so can't
+/// start one
+const CLOSING_KEYWORDS: [&str; 6] = ["then", "elif", "else", "fi", "do", "done"];
+
+/// The token stream the parser works through
+type Tokens = Peekable<std::vec::IntoIter<Token>>;
+
+/// Parse tokens into the and-or lists to run one after another; a list
+/// ending in `&` runs in the background. A trailing `;` is allowed, but an
+/// empty command between separators isn't.
+fn parse_sequence(tokens: Vec<Token>) -> Result<Vec<AndOrList>, ParseError> {
+    parse_list(&mut tokens.into_iter().peekable(), &[])
+}
+
+/// Parse and-or lists separated by `;`, `&` or newlines, until the tokens
+/// run out or one of the `until` keywords starts a command
+fn parse_list(tokens: &mut Tokens, until: &[&str]) -> Result<Vec<AndOrList>, ParseError> {
+    let mut sequence = Vec::new();
+
+    loop {
+        skip_newlines(tokens);
+        match tokens.peek() {
+            None => break,
+            Some(Token::Word(word)) if until.contains(&word.as_str()) => break,
+            _ => {}
+        }
+
+        let mut list = parse_and_or(tokens)?;
+        match tokens.next() {
+            None | Some(Token::Semi | Token::Newline) => {}
+            Some(Token::Amp) => list.background = true,
+            Some(other) => return Err(ParseError::UnexpectedToken(other.to_string())),
+        }
+        sequence.push(list);
+    }
+
+    Ok(sequence)
+}
+
+/// Parse pipelines joined by `&&` and `||`. Both operators bind equally
+/// tightly, so they are simply evaluated left to right. A newline may
+/// follow either operator.
+fn parse_and_or(tokens: &mut Tokens) -> Result<AndOrList, ParseError> {
+    let first = parse_pipeline(tokens, None)?;
+    let mut rest = Vec::new();
+
+    loop {
+        let connector = match tokens.peek() {
+            Some(Token::And) => Connector::And,
+            Some(Token::Or) => Connector::Or,
+            _ => break,
+        };
+        let operator = tokens.next();
+        skip_newlines(tokens);
+        rest.push((connector, parse_pipeline(tokens, operator)?));
+    }
+
+    Ok(AndOrList { first, rest, background: false })
+}
+
+/// Parse commands joined by `|`. `after` is the operator before the
+/// pipeline, which is what gets reported if the input stops short.
+fn parse_pipeline(tokens: &mut Tokens, after: Option<Token>) -> Result<Pipeline, ParseError> {
+    let mut commands = vec![parse_command(tokens, after)?];
+    while let Some(pipe) = tokens.next_if_eq(&Token::Pipe) {
+        skip_newlines(tokens);
+        commands.push(parse_command(tokens, Some(pipe))?);
+    }
+    Ok(commands)
+}
+
+/// Parse one command of a pipeline: a compound command, or words mixed
+/// with redirections. A missing command or redirection target is an error.
+fn parse_command(tokens: &mut Tokens, after: Option<Token>) -> Result<Command, ParseError> {
+    match tokens.peek() {
+        Some(Token::Word(word)) if word == "if" => {
+            tokens.next();
+            Ok(Command::Compound(Box::new(parse_if(tokens)?)))
+        }
+        Some(Token::Word(word)) if word == "while" || word == "until" => {
+            let until = word == "until";
+            tokens.next();
+            let (condition, _) = parse_clause(tokens, &["do"])?;
+            let (body, _) = parse_clause(tokens, &["done"])?;
+            Ok(Command::Compound(Box::new(CompoundCommand::While { until, condition, body })))
+        }
+        Some(Token::Word(word)) if word == "for" => {
+            tokens.next();
+            Ok(Command::Compound(Box::new(parse_for(tokens)?)))
+        }
+        Some(Token::Word(word)) if CLOSING_KEYWORDS.contains(&word.as_str()) => {
+            Err(ParseError::UnexpectedToken(word.clone()))
+        }
+        Some(Token::Word(_) | Token::Redirect(_)) => parse_simple_command(tokens).map(Command::Simple),
+        Some(other) => Err(ParseError::UnexpectedToken(other.to_string())),
+        None => Err(ParseError::UnexpectedToken(after.unwrap_or(Token::Newline).to_string())),
+    }
+}
+
+/// Gather words and redirections up to the next operator
+fn parse_simple_command(tokens: &mut Tokens) -> Result<SimpleCommand, ParseError> {
+    let mut command = SimpleCommand::default();
+    loop {
+        match tokens.peek() {
+            Some(Token::Word(_)) => {
+                let Some(Token::Word(word)) = tokens.next() else { unreachable!() };
+                command.words.push(word);
+            }
+            Some(&Token::Redirect(kind)) => {
+                tokens.next();
+                match tokens.next() {
+                    Some(Token::Word(target)) => command.redirects.push(Redirect { kind, target }),
+                    Some(other) => return Err(ParseError::UnexpectedToken(other.to_string())),
+                    None => return Err(ParseError::UnexpectedToken("newline".to_string())),
+                }
+            }
+            _ => return Ok(command),
+        }
+    }
+}
+
+/// Parse the rest of an `if` command, after the `if` itself
+fn parse_if(tokens: &mut Tokens) -> Result<CompoundCommand, ParseError> {
+    let mut branches = Vec::new();
+    let mut else_body = None;
+
+    loop {
+        let (condition, _) = parse_clause(tokens, &["then"])?;
+        let (body, keyword) = parse_clause(tokens, &["elif", "else", "fi"])?;
+        branches.push((condition, body));
+        match keyword {
+            "elif" => continue,
+            "else" => else_body = Some(parse_clause(tokens, &["fi"])?.0),
+            _ => {}
+        }
+        break;
+    }
+
+    Ok(CompoundCommand::If { branches, else_body })
+}
+
+/// Parse the rest of a `for` loop, after the `for` itself
+fn parse_for(tokens: &mut Tokens) -> Result<CompoundCommand, ParseError> {
+    let var = match tokens.next() {
+        Some(Token::Word(var)) if is_var_name(&var) => var,
+        Some(other) => return Err(ParseError::UnexpectedToken(other.to_string())),
+        None => return Err(ParseError::MissingKeyword("do")),
+    };
+
+    // The word list, if there is one, runs up to a `;` or newline
+    skip_newlines(tokens);
+    let mut words = None;
+    if tokens.next_if(|token| matches!(token, Token::Word(word) if word == "in")).is_some() {
+        let mut list = Vec::new();
+        while let Some(Token::Word(word)) = tokens.next_if(|token| matches!(token, Token::Word(_))) {
+            list.push(word);
+        }
+        words = Some(list);
+    }
+    tokens.next_if_eq(&Token::Semi);
+    skip_newlines(tokens);
+
+    match tokens.next() {
+        Some(Token::Word(word)) if word == "do" => {}
+        Some(other) => return Err(ParseError::UnexpectedToken(other.to_string())),
+        None => return Err(ParseError::MissingKeyword("do")),
+    }
+    let (body, _) = parse_clause(tokens, &["done"])?;
+
+    Ok(CompoundCommand::For { var, words, body })
+}
+
+/// Parse a non-empty list of commands ending with one of the `until`
+/// keywords, which is consumed and returned. Running out of input first
+/// means the last keyword is still to come.
+fn parse_clause(tokens: &mut Tokens, until: &[&'static str]) -> Result<(Vec<AndOrList>, &'static str), ParseError> {
+    let list = parse_list(tokens, until)?;
+    let keyword = match tokens.next() {
+        Some(Token::Word(word)) => until.iter().find(|keyword| **keyword == word).unwrap(),
+        _ => return Err(ParseError::MissingKeyword(until.last().unwrap())),
+    };
+    if list.is_empty() {
+        return Err(ParseError::UnexpectedToken(keyword.to_string()));
+    }
+    Ok((list, keyword))
+}
+
+fn skip_newlines(tokens: &mut Tokens) {
+    while tokens.next_if_eq(&Token::Newline).is_some() {}
+}
+
+/// A word after expansion. Alongside the plain text we build the glob
+/// pattern for the word, with any quoted or escaped characters escaped so
+/// that only unquoted `*`, `?` and `[` act as wildcards.
+#[derive(Debug, Default, PartialEq)]
+pub struct ExpandedWord {
+    pub text: String,
+    pub pattern: String,
+    /// Set when the word has an unquoted glob character
+    pub has_glob: bool,
+    /// Where `$@` put one positional parameter after the next, as offsets
+    /// into `text` and `pattern` of the space joining them
+    pub breaks: Vec<(usize, usize)>,
+    /// Set when `$@` had no parameters to give, so a word of nothing else
+    /// becomes no words at all
+    pub empty_at: bool,
+    /// Set when the word has quotes, so it's still a word if it's empty
+    pub quoted: bool,
+}
+
+impl ExpandedWord {
+    /// Add text that must match literally (quoted, escaped or from `~`)
+    fn push_literal(&mut self, s: &str) {
+        self.text.push_str(s);
+        self.pattern.push_str(&glob::Pattern::escape(s));
+    }
+
+    /// Add an unquoted character, which may be a glob wildcard
+    fn push_unquoted(&mut self, c: char) {
+        self.text.push(c);
+        self.pattern.push(c);
+        if matches!(c, '*' | '?' | '[') {
+            self.has_glob = true;
+        }
+    }
+
+    /// Add the result of a variable expansion
+    fn push_expansion(&mut self, value: &str, quoted: bool) {
+        if quoted {
+            self.push_literal(value);
+        } else {
+            value.chars().for_each(|c| self.push_unquoted(c));
+        }
+    }
+
+    /// End one field of a `$@` expansion. The text reads as the fields
+    /// joined by spaces until `into_fields` separates them.
+    fn push_break(&mut self) {
+        self.breaks.push((self.text.len(), self.pattern.len()));
+        self.text.push(' ');
+        self.pattern.push(' ');
+    }
+
+    /// Split the word where `$@` ended each field. An unquoted expansion
+    /// that came to nothing gives no field at all, but `""` is still one.
+    fn into_fields(self) -> Vec<ExpandedWord> {
+        if self.text.is_empty() && (self.empty_at || !self.quoted) {
+            return Vec::new();
+        }
+        if self.breaks.is_empty() {
+            return vec![self];
+        }
+
+        let mut fields = Vec::new();
+        let (mut text_start, mut pattern_start) = (0, 0);
+        let ends = self.breaks.iter().copied().chain([(self.text.len(), self.pattern.len())]);
+        for (text_end, pattern_end) in ends {
+            fields.push(ExpandedWord {
+                text: self.text[text_start..text_end].to_string(),
+                pattern: self.pattern[pattern_start..pattern_end].to_string(),
+                has_glob: self.has_glob,
+                ..ExpandedWord::default()
+            });
+            (text_start, pattern_start) = (text_end + 1, pattern_end + 1);
+        }
+        fields
+    }
+}
+
+/// Does a glob match pick up a hidden file or directory that the pattern
+/// didn't name with a leading `.`? The `glob` crate's own option for this
+/// also hides files from patterns like `.*`, so we check it ourselves.
+fn is_hidden_match(pattern: &str, path: &str) -> bool {
+    pattern
+        .split('/')
+        .zip(path.split('/'))
+        .any(|(pat, name)| name.starts_with('.') && !pat.starts_with('.'))
+}
+
+/// Fully expand a single word: a leading `~` first, then variables,
+/// quotes and escapes
+fn expand_word(word: &str, env: &ShellEnv) -> ExpandedWord {
+    let mut expanded = ExpandedWord::default();
+    let (home, rest) = expand_tilde(word, env);
+    expanded.push_literal(&home);
+    expand_into(rest, env, &mut expanded);
+    expanded
+}
+
+/// Expand a `~` or `~user` at the start of a word, when it is followed by
+/// `/` or the end of the word. Returns the expansion and the rest of the
+/// word still to be processed. Anything we can't resolve is left as is.
+fn expand_tilde<'a>(word: &'a str, env: &ShellEnv) -> (String, &'a str) {
+    let Some(after) = word.strip_prefix('~') else {
+        return (String::new(), word);
+    };
+
+    let end = after.find('/').unwrap_or(after.len());
+    let user = &after[..end];
+    let home = if user.is_empty() {
+        env.get_var("HOME").cloned()
+    } else if user.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        user_home(user)
+    } else {
+        // Quotes, escapes or `$` in the name mean it isn't a plain user name
+        None
+    };
+
+    match home {
+        Some(home) => (home, &after[end..]),
+        None => (String::new(), word),
+    }
+}
+
+/// Look up a user's home directory in /etc/passwd
+fn user_home(user: &str) -> Option<String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    home_from_passwd(&passwd, user)
+}
+
+/// Find a user's home directory in the contents of a passwd file, where
+/// each line is `name:password:uid:gid:gecos:home:shell`
+fn home_from_passwd(passwd: &str, user: &str) -> Option<String> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 6 && fields[0] == user {
+            Some(fields[5].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Expand a single token into `result`: substitute `$NAME`, `${NAME}` and
+/// `$?` from the environment and strip the quotes and escapes, keeping the
+/// quoting of each character for globbing. Unknown variables expand to
+/// nothing, like bash. Outside quotes a backslash escapes any character
+/// (so `\$HOME` stays literal); inside double quotes it only escapes `"`,
+/// `\`, `$` and newline. Single quotes keep everything literal.
+fn expand_into(word: &str, env: &ShellEnv, result: &mut ExpandedWord) {
+    let mut quote: Option<char> = None;
+    let mut chars = word.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                result.quoted = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None, '\\') => match chars.next() {
+                Some('\n') => {}
+                Some(next) => result.push_literal(&next.to_string()),
+                // A trailing backslash has nothing to escape
+                None => result.push_literal("\\"),
+            },
+            (Some('"'), '\\') => match chars.peek() {
+                Some('\n') => {
+                    chars.next();
+                }
+                Some(&next) if matches!(next, '"' | '\\' | '$') => {
+                    result.push_literal(&next.to_string());
+                    chars.next();
+                }
+                _ => result.push_literal("\\"),
+            },
+            (None | Some('"'), '$') => expand_dollar(&mut chars, env, result, quote.is_some()),
+            (None, c) => result.push_unquoted(c),
+            _ => result.push_literal(&c.to_string()),
+        }
+    }
+}
+
+/// Expand the variable reference following a `$`, including the special
+/// parameters such as `$?`, `$$`, `$#`, `$1` and `$@`. Anything that
+/// isn't a valid reference leaves the `$` as literal text.
+fn expand_dollar(
+    chars: &mut Peekable<Chars>,
+    env: &ShellEnv,
+    result: &mut ExpandedWord,
+    quoted: bool,
+) {
+    match chars.peek() {
+        Some('?') => {
+            chars.next();
+            result.push_literal(&env.last_status.to_string());
+        }
+        Some('#') => {
+            chars.next();
+            result.push_literal(&env.args.len().saturating_sub(1).to_string());
+        }
+        Some('$') => {
+            chars.next();
+            result.push_literal(&env.pid.to_string());
+        }
+        Some('@') => {
+            // Each parameter is a word of its own, even in double quotes
+            chars.next();
+            let params = env.args.get(1..).unwrap_or_default();
+            result.empty_at |= params.is_empty();
+            for (i, param) in params.iter().enumerate() {
+                if i > 0 {
+                    result.push_break();
+                }
+                result.push_expansion(param, quoted);
+            }
+        }
+        Some('*') => {
+            // All the parameters as one word, joined by the first IFS character
+            chars.next();
+            let params = env.args.get(1..).unwrap_or_default();
+            let separator: String = env.ifs().chars().take(1).collect();
+            result.push_expansion(&params.join(&separator), quoted);
+        }
+        Some(&c) if c.is_ascii_digit() => {
+            // Without braces only a single digit is read, like bash
+            chars.next();
+            result.push_expansion(&lookup_var(&c.to_string(), env), quoted);
+        }
+        Some('{') => {
+            // Only treat it as a reference if the brace is closed
+            let mut lookahead = chars.clone();
+            lookahead.next();
+            let name: String = lookahead.by_ref().take_while(|&c| c != '}').collect();
+            let positional = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+            if chars.clone().skip(1).any(|c| c == '}') && (is_var_name(&name) || positional) {
+                *chars = lookahead;
+                result.push_expansion(&lookup_var(&name, env), quoted);
+            } else {
+                result.push_literal("$");
+            }
+        }
+        Some(&c) if c == '_' || c.is_ascii_alphabetic() => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == '_' || c.is_ascii_alphanumeric() {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            result.push_expansion(&lookup_var(&name, env), quoted);
+        }
+        _ => result.push_literal("$"),
+    }
+}
+
+/// Is this a valid variable name (letters, digits and `_`, not starting
+/// with a digit)?
+pub fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+/// Look up a variable (or a positional parameter, for a number) for
+/// expansion; unset variables expand to nothing
+fn lookup_var(name: &str, env: &ShellEnv) -> String {
+    match name.parse::<usize>() {
+        Ok(index) => env.args.get(index).cloned().unwrap_or_default(),
+        Err(_) => env.get_var(name).cloned().unwrap_or_default(),
+    }
+}
+
+fn as_str_vec(strings: &[String]) -> Vec<&str> {
+    strings.iter().map(|s| s.as_str()).collect::<Vec<_>>()
+}
+
+/// Use the environment to expand our argument list, then expand any
+/// wildcards against the filesystem
+fn expand_args(args: &[&str], env: &ShellEnv) -> Vec<String> {
+    expand_globs(args.iter().flat_map(|arg| expand_word(arg, env).into_fields()).collect())
+}
+
+/// Replace each word that has unquoted glob characters with the paths it
+/// matches, sorted. A pattern that matches nothing is left as it was,
+/// like bash. Hidden files only match a pattern that starts with `.`.
+fn expand_globs(words: Vec<ExpandedWord>) -> Vec<String> {
+    let mut result = Vec::new();
+
+    for word in words {
+        if word.has_glob && let Ok(paths) = glob::glob(&word.pattern) {
+            let mut matches: Vec<String> = paths
+                .flatten()
+                .map(|path| path.to_string_lossy().into_owned())
+                .filter(|path| !is_hidden_match(&word.pattern, path))
+                .collect();
+            if !matches.is_empty() {
+                matches.sort();
+                result.append(&mut matches);
+                continue;
+            }
+        }
+        result.push(word.text);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::builtin_set;
+
+    use serial_test::serial;
+
+    #[test]
+    fn test_expanded_args_no_dollar() {
+        let env = ShellEnv::empty();
+        let args = ["hello"];
+        let expanded_args = expand_args(&args, &env);
+
+        assert_eq!(expanded_args.len(), args.len());
+        assert_eq!(expanded_args[0], "hello");
+    }
+
+    #[test]
+    fn test_expanded_args_dollar_no_match() {
+        let env = ShellEnv::empty();
+        let args = ["$HELLO"];
+        let expanded_args = expand_args(&args, &env);
+
+        // Unset variables expand to nothing, like bash, so leave no word
+        assert!(expanded_args.is_empty());
+    }
+
+    #[test]
+    fn test_expanded_args_empty_fields() {
+        let mut env = ShellEnv::empty();
+        env.set_var("EMPTY", "");
+
+        assert_eq!(expand_args(&["cmd", "$EMPTY", "x", "$UNSET$EMPTY"], &env), ["cmd", "x"]);
+        // Quotes make an empty word a real argument
+        assert_eq!(expand_args(&["\"\"", "''", "\"$EMPTY\"", "$UNSET''"], &env), ["", "", "", ""]);
+    }
+
+    #[test]
+    fn test_empty_expansion_is_not_a_command() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("");
+        handle_command("$UNSET echo hi; echo \"$UNSET\" | wc -c", &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap().replace(' ', ""), "hi\n1\n");
+        assert_eq!(env.last_status, 0);
+        assert_eq!(io.stderr.unwrap(), b"");
+    }
+
+    #[test]
+    fn test_expanded_args_dollar_match() {
+        let mut env = ShellEnv::empty();
+        let args = ["$HELLO"];
+        env.set_var("HELLO", "world");
+        let expanded_args = expand_args(&args, &env);
+
+        assert_eq!(expanded_args.len(), args.len());
+        assert_eq!(expanded_args[0], "world");
+    }
+
+    #[test]
+    fn test_expanded_args_mixed() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HELLO", "world");
+        env.set_var("THERE", "Rust");
+
+        let args = ["say", "$HELLO", "to", "$THERE"];
+
+        let expanded_args = expand_args(&args, &env);
+
+        assert_eq!(expanded_args.len(), args.len());
+        assert_eq!(expanded_args, ["say", "world", "to", "Rust"]);
+    }
+
+    /// In-memory streams all round, with `input` waiting to be read
+    fn buffered(input: &str) -> ShellIo {
+        ShellIo {
+            stdin: Some(input.as_bytes().to_vec()),
+            stdout: Some(Vec::new()),
+            stderr: Some(Vec::new()),
+        }
+    }
+
+    /// The simple command a parsed pipeline stage should be
+    fn simple(command: &Command) -> &SimpleCommand {
+        match command {
+            Command::Simple(command) => command,
+            other => panic!("expected a simple command, got {}", other),
+        }
+    }
+
+    /// The commands of the first pipeline on a line
+    fn first_pipeline(input: &str) -> Pipeline {
+        parse_sequence(tokenize(input).unwrap()).unwrap().remove(0).first
+    }
+
+    /// Tokenize and expand a line the way handle_command sees it
+    fn words(input: &str) -> Vec<String> {
+        let env = ShellEnv::empty();
+        let commands = first_pipeline(input);
+        expand_args(&as_str_vec(&simple(&commands[0]).words), &env)
+    }
+
+    #[test]
+    fn test_tokenize_plain_words() {
+        let tokens = tokenize("ls   -la  /tmp").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("ls".to_string()),
+                Token::Word("-la".to_string()),
+                Token::Word("/tmp".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_mixed_quoting_is_one_word() {
+        let tokens = tokenize("echo 'a  b'\"c $d\"e\\ f").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("echo".to_string()),
+                Token::Word("'a  b'\"c $d\"e\\ f".to_string()),
+            ]
+        );
+        assert_eq!(words("echo 'a  b'\"c d\"e\\ f"), ["echo", "a  bc de f"]);
+    }
+
+    #[test]
+    fn test_tokenize_mixed_quoting_with_expansion() {
+        let mut env = ShellEnv::empty();
+        env.set_var("X", "1 2");
+
+        let tokens = tokenize("echo \"$X\"'$X'$X").unwrap();
+        let Token::Word(word) = &tokens[1] else { panic!("expected a word") };
+        assert_eq!(expand_args(&[word], &env), ["1 2$X1 2"]);
+    }
+
+    #[test]
+    fn test_tokenize_quoted_operators_stay_in_word() {
+        let tokens = tokenize("echo \"a | b > c\" 'd >> e'").unwrap();
+        assert_eq!(tokens.len(), 3);
+        assert!(tokens.iter().all(|t| matches!(t, Token::Word(_))));
+    }
+
+    #[test]
+    fn test_tokenize_comments() {
+        assert_eq!(words("echo hi # comment"), ["echo", "hi"]);
+        assert_eq!(words("echo a#b"), ["echo", "a#b"]);
+        assert_eq!(words("echo \"# not a comment\" '#' \\#"), ["echo", "# not a comment", "#", "#"]);
+        assert_eq!(tokenize("# just a comment").unwrap(), []);
+        assert_eq!(tokenize("ls;# done").unwrap(), [Token::Word("ls".to_string()), Token::Semi]);
+        // The comment ends at the end of the line
+        assert_eq!(
+            tokenize("a # b\nc").unwrap(),
+            [Token::Word("a".to_string()), Token::Newline, Token::Word("c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_heredoc() {
+        assert_eq!(
+            tokenize("cat <<EOF\none\ntwo\nEOF\necho after").unwrap(),
+            [
+                Token::Word("cat".to_string()),
+                Token::Redirect(RedirectKind::HereDoc { expand: true }),
+                Token::Word("one\ntwo\n".to_string()),
+                Token::Newline,
+                Token::Word("echo".to_string()),
+                Token::Word("after".to_string()),
+            ]
+        );
+
+        // A quoted delimiter turns off expansion; `<<-` strips tabs
+        assert_eq!(
+            tokenize("cat <<-'END'\n\t$X\n\tEND").unwrap()[1..],
+            [
+                Token::Redirect(RedirectKind::HereDoc { expand: false }),
+                Token::Word("$X\n".to_string()),
+                Token::Newline,
+            ]
+        );
+        assert!(matches!(
+            tokenize("cat <<EOF\nno end"),
+            Err(ParseError::UnterminatedHereDoc(delimiter)) if delimiter == "EOF"
+        ));
+    }
+
+    #[test]
+    fn test_needs_more_input() {
+        assert!(needs_more_input("echo 'open"));
+        assert!(needs_more_input("echo \"open"));
+        assert!(needs_more_input("echo a \\"));
+        assert!(!needs_more_input("echo a \\\\"));
+        assert!(!needs_more_input("echo 'closed\\'"));
+        assert!(!needs_more_input("echo done"));
+    }
+
+    #[test]
+    fn test_needs_more_input_after_operator() {
+        assert!(needs_more_input("ls |"));
+        assert!(needs_more_input("true &&"));
+        assert!(needs_more_input("false || "));
+        assert!(needs_more_input("echo $(ls"));
+        assert!(needs_more_input("echo \"$(echo 'a"));
+        assert!(!needs_more_input("echo $(ls)"));
+        assert!(!needs_more_input("echo '|'"));
+        // Without a command first it's an error, not a continuation
+        assert!(!needs_more_input("|"));
+        assert!(!needs_more_input("ls; &&"));
+    }
+
+    #[test]
+    fn test_read_complete_command_after_pipe() {
+        let mut lines = vec!["wc -c".to_string()].into_iter();
+        let input = read_complete_command("echo abc |".to_string(), || lines.next()).unwrap();
+        assert_eq!(input, "echo abc |\nwc -c");
+
+        let mut env = ShellEnv::new();
+        assert_eq!(capture_output(&input, &mut env, &crate::builtins::builtins(), &mut ShellIo::default()).trim(), "4");
+    }
+
+    #[test]
+    fn test_needs_more_input_inside_heredoc() {
+        assert!(needs_more_input("cat <<EOF"));
+        assert!(needs_more_input("cat <<EOF\nline"));
+        assert!(!needs_more_input("cat <<EOF\nline\nEOF"));
+    }
+
+    #[test]
+    fn test_needs_more_input_inside_if() {
+        assert!(needs_more_input("if true"));
+        assert!(needs_more_input("if true; then"));
+        assert!(needs_more_input("if true; then echo hi\nelse"));
+        assert!(!needs_more_input("if true; then echo hi; fi"));
+        // A mistake won't be fixed by reading more, so isn't incomplete
+        assert!(!needs_more_input("if then"));
+    }
+
+    #[test]
+    fn test_backslash_newline_joins_lines() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("set JOINED one\\\ntwo", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("JOINED").unwrap(), "onetwo");
+
+        assert_eq!(words("echo a \\\n b"), ["echo", "a", "b"]);
+    }
+
+    #[test]
+    fn test_read_complete_command_joins_quoted_lines() {
+        let mut more = vec!["second line'".to_string()].into_iter();
+        let command = read_complete_command("echo 'first line".to_string(), || more.next()).unwrap();
+        assert_eq!(command, "echo 'first line\nsecond line'");
+        assert_eq!(words(&command), ["echo", "first line\nsecond line"]);
+
+        let mut more = vec!["b \\".to_string(), "c".to_string()].into_iter();
+        let command = read_complete_command("echo a \\".to_string(), || more.next()).unwrap();
+        assert_eq!(words(&command), ["echo", "a", "b", "c"]);
+
+        // Complete input doesn't ask for more
+        let command = read_complete_command("echo hi".to_string(), || panic!("asked for more")).unwrap();
+        assert_eq!(command, "echo hi");
+    }
+
+    #[test]
+    fn test_read_complete_command_gives_up() {
+        assert_eq!(read_complete_command("echo \"oops".to_string(), || None), None);
+    }
+
+    #[test]
+    fn test_command_substitution_captures_output() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        assert_eq!(capture_output("echo hello", &mut env, &builtins, &mut ShellIo::default()), "hello");
+        assert_eq!(capture_output("printf 'a\\nb\\n\\n'", &mut env, &builtins, &mut ShellIo::default()), "a\nb");
+        assert_eq!(capture_output("echo one | tr a-z A-Z; echo two", &mut env, &builtins, &mut ShellIo::default()), "ONE\ntwo");
+
+        handle_command("set GREETING \"say $(echo hi there)\"", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("GREETING").unwrap(), "say hi there");
+    }
+
+    #[test]
+    #[serial]
+    fn test_pipeline_runs_in_memory() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("pear\napple\nfig\n");
+        handle_command("sort | head -n 2; echo done; cd /nonexistent", &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap(), "apple\nfig\ndone\n");
+        assert!(String::from_utf8(io.stderr.unwrap()).unwrap().starts_with("cd: /nonexistent: "));
+
+        // `read` takes a line at a time, leaving the rest for the next reader
+        let mut io = buffered("first line\nsecond\nthird\n");
+        let script = "read A; read B <<EOF\nfrom heredoc\nEOF\nwhile read L; do echo [$L]; done; echo $A/$B";
+        run_lines(script, &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap(), "[second]\n[third]\nfirst line/from heredoc\n");
+    }
+
+    #[test]
+    #[serial]
+    fn test_pipeline_with_builtins() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        let run = |line: &str, env: &mut ShellEnv| {
+            let mut io = buffered("");
+            handle_command(line, env, &builtins, &mut io);
+            (String::from_utf8(io.stdout.unwrap()).unwrap(), String::from_utf8(io.stderr.unwrap()).unwrap())
+        };
+
+        // A builtin's output goes to the next stage, wherever it is
+        assert_eq!(run("echo hi | cat", &mut env).0, "hi\n");
+        assert_eq!(run("echo b a | tr ' ' '\\n' | sort", &mut env).0, "a\nb\n");
+        assert_eq!(run("printf 'x\\ny\\n' | echo last", &mut env).0, "last\n");
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(run("pwd | cat", &mut env).0, format!("{}\n", cwd.display()));
+
+        // The status is the last stage's, and errors still reach stderr
+        let (out, err) = run("cd /nonexistent | echo after", &mut env);
+        assert_eq!(out, "after\n");
+        assert!(err.starts_with("cd: /nonexistent: "));
+        assert_eq!(env.last_status, 0);
+        run("echo hi | false", &mut env);
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_pipeline_empty_stage_passes_input_through() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("");
+        handle_command("printf 'a\\nb\\n' | $EMPTY | wc -l", &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap().trim(), "2");
+        assert_eq!(env.last_status, 0);
+
+        let mut io = buffered("one\ntwo\n");
+        handle_command("$EMPTY | wc -l", &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap().trim(), "2");
+    }
+
+    #[test]
+    fn test_command_substitution_word_splitting() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        let words = |input: &str, env: &mut ShellEnv| {
+            let commands = first_pipeline(input);
+            let words = substitute_commands(&simple(&commands[0]).words, env, &builtins, &mut ShellIo::default()).unwrap();
+            expand_args(&as_str_vec(&words), env)
+        };
+
+        // Unquoted output splits into words; quoted output stays whole
+        assert_eq!(words("echo $(echo a   b)", &mut env), ["echo", "a", "b"]);
+        assert_eq!(words("echo \"$(echo a   b)\"", &mut env), ["echo", "a b"]);
+        assert_eq!(words("echo x$(echo y)z", &mut env), ["echo", "xyz"]);
+        assert_eq!(words("echo $(true)", &mut env), ["echo"]);
+        // Output is never re-read as shell syntax
+        assert_eq!(words("echo $(echo '$HOME \"*\" ;')", &mut env), ["echo", "$HOME", "\"*\"", ";"]);
+        assert_eq!(words("echo '$(echo no)'", &mut env), ["echo", "$(echo no)"]);
+    }
+
+    #[test]
+    fn test_nested_command_substitution() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let input = "echo $(echo $(echo inner) \"$(echo \"quoted ) paren\")\")";
+        let output = capture_output(input, &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(output, "inner quoted ) paren");
+    }
+
+    #[test]
+    fn test_command_substitution_splits_on_ifs() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        let run = |input: &str, env: &mut ShellEnv| capture_output(input, env, &builtins, &mut ShellIo::default());
+
+        env.set_var("IFS", ":");
+        assert_eq!(run("printf '%s\\n' $(echo a:b c)", &mut env), "a\nb c");
+        assert_eq!(run("printf '%s|' $(printf 'x\\ny')", &mut env), "x\ny|");
+        assert_eq!(run("printf '%s\\n' \"$(echo a:b)\"", &mut env), "a:b");
+
+        env.set_var("IFS", "");
+        assert_eq!(run("printf '%s\\n' $(echo a b)", &mut env), "a b");
+    }
+
+    #[test]
+    fn test_command_substitution_sees_shell_variables() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+        env.set_var("LOCAL", "not exported");
+
+        assert_eq!(capture_output("echo $(echo $LOCAL)", &mut env, &builtins, &mut ShellIo::default()), "not exported");
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+        env.set_var("n", "4");
+
+        assert_eq!(capture_output("echo $((2 + 3 * 4))", &mut env, &builtins, &mut ShellIo::default()), "14");
+        assert_eq!(capture_output("echo \"$(( (n + 1) * $n ))\" x$((n % 3))", &mut env, &builtins, &mut ShellIo::default()), "20 x1");
+        assert_eq!(capture_output("echo '$((1 + 1))'", &mut env, &builtins, &mut ShellIo::default()), "$((1 + 1))");
+
+        // Division by zero fails the command without running it
+        assert_eq!(capture_output("echo $((1 / 0))", &mut env, &builtins, &mut ShellIo::default()), "");
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_let_with_quoted_expressions() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("let \"z = 3\" x=1; let 'x += 1' \"x *= z\"", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("z").unwrap(), "3");
+        assert_eq!(env.get_var("x").unwrap(), "6");
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_heredoc_feeds_stdin() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        env.set_var("X", "value");
+
+        let output = capture_output("cat <<EOF\nfirst $X\nsecond \"$(echo sub)\"\nEOF", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(output, "first value\nsecond \"sub\"");
+        assert_eq!(capture_output("cat <<'EOF'\n$X \\$\nEOF", &mut env, &builtins, &mut ShellIo::default()), "$X \\$");
+        assert_eq!(capture_output("cat <<EOF | tr a-z A-Z\nshout\nEOF", &mut env, &builtins, &mut ShellIo::default()), "SHOUT");
+    }
+
+    #[test]
+    fn test_heredoc_through_shell_io() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        // The here-document, not the shell's input, is what cat reads
+        let mut io = buffered("left for read\n");
+        let script = "cat <<-END\n\tindented $((1 + 1))\n\t\tdeeper\n\tEND\nread REST; echo $REST";
+        run_lines(script, &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stdout.unwrap()).unwrap(), "indented 2\ndeeper\nleft for read\n");
+        assert_eq!(io.stderr.unwrap(), b"");
+    }
+
+    #[test]
+    fn test_is_assignment() {
+        assert!(is_assignment("X=1"));
+        assert!(is_assignment("_a9="));
+        assert!(is_assignment("X=\"a b\""));
+        assert!(!is_assignment("=1"));
+        assert!(!is_assignment("9X=1"));
+        assert!(!is_assignment("'X'=1"));
+        assert!(!is_assignment("a-b=1"));
+        assert!(!is_assignment("echo"));
+    }
+
+    #[test]
+    fn test_bare_assignment_sets_shell_variable() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("A=1 B=\"two  words\" C=$(echo x y) D=*", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("A").unwrap(), "1");
+        assert_eq!(env.get_var("B").unwrap(), "two  words");
+        assert_eq!(env.get_var("C").unwrap(), "x y");
+        assert_eq!(env.get_var("D").unwrap(), "*");
+        assert!(!env.exported.contains("A"));
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_assignment_prefix_applies_to_one_command() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        env.unset_var("X");
+        env.set_var("Y", "old");
+
+        assert_eq!(capture_output("X=1 sh -c 'echo $X'", &mut env, &builtins, &mut ShellIo::default()), "1");
+        // The builtin `env` and the external one in a pipeline both see them
+        assert_eq!(capture_output("X=1 Y=new env | grep '^[XY]=' | sort", &mut env, &builtins, &mut ShellIo::default()), "X=1\nY=new");
+        let builtin_env = capture_output("X=1 Y=new env", &mut env, &builtins, &mut ShellIo::default());
+        assert!(builtin_env.lines().any(|line| line == "X=1"));
+        assert!(builtin_env.lines().any(|line| line == "Y=new"));
+        assert_eq!(env.get_var("X"), None);
+        assert_eq!(env.get_var("Y").unwrap(), "old");
+        assert!(!env.exported.contains("Y"));
+    }
+
+    #[test]
+    fn test_assignment_prefix_restores_exported_value() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        handle_command("export E=orig", &mut env, &builtins, &mut ShellIo::default());
+
+        assert_eq!(capture_output("E=tmp sh -c 'echo $E'", &mut env, &builtins, &mut ShellIo::default()), "tmp");
+        assert_eq!(env.get_var("E").unwrap(), "orig");
+        assert!(env.exported.contains("E"));
+
+        // Even when the command can't be found
+        handle_command("Z=1 no-such-command-here", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("Z"), None);
+    }
+
+    #[test]
+    fn test_tokenize_keeps_substitution_in_one_word() {
+        let tokens = tokenize("echo $(ls | wc -l; echo ')') \"$(echo \"a b\")\"").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("echo".to_string()),
+                Token::Word("$(ls | wc -l; echo ')')".to_string()),
+                Token::Word("\"$(echo \"a b\")\"".to_string()),
+            ]
+        );
+        assert_eq!(tokenize("echo $(echo"), Err(ParseError::UnterminatedQuote(')')));
+        assert!(needs_more_input("echo $(echo"));
+    }
+
+    #[test]
+    fn test_should_record_history_default() {
+        let env = ShellEnv::empty();
+        assert!(should_record_history("ls", None, &env));
+        assert!(should_record_history("ls", Some("pwd"), &env));
+        assert!(should_record_history(" ls", Some("pwd"), &env));
+        assert!(!should_record_history("ls", Some("ls"), &env));
+        assert!(!should_record_history("", None, &env));
+        assert!(!should_record_history("  \t", Some("ls"), &env));
+    }
+
+    #[test]
+    fn test_should_record_history_modes() {
+        let mut env = ShellEnv::empty();
+
+        env.set_var("HISTCONTROL", "ignorespace");
+        assert!(!should_record_history(" ls", None, &env));
+        assert!(should_record_history("ls", Some("ls"), &env));
+
+        env.set_var("HISTCONTROL", "ignoreboth");
+        assert!(!should_record_history(" ls", None, &env));
+        assert!(!should_record_history("ls", Some("ls"), &env));
+        assert!(should_record_history("ls", Some("pwd"), &env));
+
+        env.set_var("HISTCONTROL", "ignorespace:ignoredups");
+        assert!(!should_record_history(" ls", None, &env));
+        assert!(!should_record_history("ls", Some("ls"), &env));
+
+        // Anything else records every non-blank line
+        env.set_var("HISTCONTROL", "");
+        assert!(should_record_history(" ls", Some(" ls"), &env));
+        assert!(!should_record_history(" ", None, &env));
+    }
+
+    /// A small history to expand against
+    fn sample_history() -> Vec<String> {
+        ["ls -la", "echo one", "cd /tmp", "echo two"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn test_expand_history_previous_command() {
+        let history = sample_history();
+        assert_eq!(expand_history("!!", &history), Ok(Some("echo two".to_string())));
+        assert_eq!(expand_history("!! | wc", &history), Ok(Some("echo two | wc".to_string())));
+        assert_eq!(expand_history("!!", &[]), Err("!!: event not found".to_string()));
+    }
+
+    #[test]
+    fn test_expand_history_by_number_and_prefix() {
+        let history = sample_history();
+        assert_eq!(expand_history("!1", &history), Ok(Some("ls -la".to_string())));
+        assert_eq!(expand_history("!-2", &history), Ok(Some("cd /tmp".to_string())));
+        assert_eq!(expand_history("!echo", &history), Ok(Some("echo two".to_string())));
+        assert_eq!(expand_history("!l; !c", &history), Ok(Some("ls -la; cd /tmp".to_string())));
+    }
+
+    #[test]
+    fn test_expand_history_missing_event() {
+        let history = sample_history();
+        assert_eq!(expand_history("!99", &history), Err("!99: event not found".to_string()));
+        assert_eq!(expand_history("!nope", &history), Err("!nope: event not found".to_string()));
+    }
+
+    #[test]
+    fn test_expand_history_leaves_other_bangs() {
+        let history = sample_history();
+        assert_eq!(expand_history("echo hi", &history), Ok(None));
+        assert_eq!(expand_history("echo '!!' \\!! !", &history), Ok(None));
+        assert_eq!(expand_history("test ! -z x", &history), Ok(None));
+        assert_eq!(expand_history("echo \"!!\"", &history), Ok(Some("echo \"echo two\"".to_string())));
+    }
+
+    #[test]
+    fn test_expand_history_bang_before_double_quote() {
+        let history = sample_history();
+        assert_eq!(expand_history("echo \"wow!\"", &history), Ok(None));
+        assert_eq!(expand_history("echo \"wow!\" !!", &history), Ok(Some("echo \"wow!\" echo two".to_string())));
+    }
+
+    #[test]
+    fn test_expand_history_apostrophe_in_double_quotes() {
+        let history = sample_history();
+        assert_eq!(expand_history("echo \"it's !!\"", &history), Ok(Some("echo \"it's echo two\"".to_string())));
+        // Double quotes inside single quotes don't count either
+        assert_eq!(expand_history("echo '\"' !!", &history), Ok(Some("echo '\"' echo two".to_string())));
+        assert_eq!(expand_history("echo '\" !!'", &history), Ok(None));
+    }
+
+    #[test]
+    fn test_comment_line_does_nothing() {
+        let mut env = ShellEnv::empty();
+        env.last_status = 3;
+
+        handle_command("# echo should not run", &mut env, &crate::builtins::builtins(), &mut ShellIo::default());
+        assert_eq!(env.last_status, 3);
+    }
+
+    #[test]
+    fn test_parse_error_is_std_error() {
+        let err: Box<dyn std::error::Error> = Box::new(tokenize("echo 'oops").unwrap_err());
+        assert!(err.to_string().contains("matching `''"));
+    }
+
+    #[test]
+    fn test_double_quotes_preserve_whitespace() {
+        assert_eq!(words("echo \"a  b\""), ["echo", "a  b"]);
+    }
+
+    #[test]
+    fn test_single_quotes_preserve_whitespace() {
+        assert_eq!(words("echo 'a b'"), ["echo", "a b"]);
+    }
+
+    #[test]
+    fn test_single_quote_inside_double_quotes() {
+        assert_eq!(words("echo \"it's\""), ["echo", "it's"]);
+        assert_eq!(words("echo 'say \"hi\"'"), ["echo", "say \"hi\""]);
+    }
+
+    #[test]
+    fn test_quotes_join_adjacent_text() {
+        assert_eq!(words("echo foo\"bar baz\"qux"), ["echo", "foobar bazqux"]);
+        assert_eq!(words("echo ''"), ["echo", ""]);
+    }
+
+    #[test]
+    fn test_single_quotes_are_not_expanded() {
+        let mut env = ShellEnv::empty();
+        env.set_var("FOO", "bar");
+        let args = ["'$FOO'"];
+
+        assert_eq!(expand_args(&args, &env), ["$FOO"]);
+    }
+
+    #[test]
+    fn test_unterminated_quote_is_error() {
+        let err = tokenize("echo \"abc").unwrap_err();
+        assert_eq!(err, ParseError::UnterminatedQuote('"'));
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of input while looking for matching `\"'"
+        );
+
+        assert_eq!(tokenize("echo 'abc").unwrap_err(), ParseError::UnterminatedQuote('\''));
+    }
+
+    #[test]
+    fn test_backslash_escapes_space() {
+        assert_eq!(words("echo a\\ b"), ["echo", "a b"]);
+    }
+
+    #[test]
+    fn test_backslash_escapes_backslash() {
+        assert_eq!(words("echo \\\\"), ["echo", "\\"]);
+    }
+
+    #[test]
+    fn test_backslash_escapes_quotes_and_operators() {
+        assert_eq!(words("echo \\\"hi\\\" \\'"), ["echo", "\"hi\"", "'"]);
+        assert_eq!(words("echo a\\|b"), ["echo", "a|b"]);
+    }
+
+    #[test]
+    fn test_backslash_in_double_quotes() {
+        assert_eq!(words("echo \"\\$x\""), ["echo", "$x"]);
+        assert_eq!(words("echo \"a\\\"b\""), ["echo", "a\"b"]);
+        assert_eq!(words("echo \"a\\\\b\""), ["echo", "a\\b"]);
+        // Other characters keep their backslash
+        assert_eq!(words("echo \"a\\nb\""), ["echo", "a\\nb"]);
+    }
+
+    #[test]
+    fn test_backslash_in_single_quotes_is_literal() {
+        assert_eq!(words("echo 'a\\b'"), ["echo", "a\\b"]);
+    }
+
+    #[test]
+    fn test_escaped_dollar_is_not_expanded() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+        let args = ["\\$HOME"];
+
+        assert_eq!(expand_args(&args, &env), ["$HOME"]);
+    }
+
+    #[test]
+    fn test_trailing_backslash_is_kept() {
+        assert_eq!(words("echo abc\\"), ["echo", "abc\\"]);
+    }
+
+    #[test]
+    fn test_pipe_inside_quotes_is_literal() {
+        let commands = first_pipeline("echo 'a|b' | cat");
+        assert_eq!(simple(&commands[0]).words, ["echo", "'a|b'"]);
+        assert_eq!(simple(&commands[1]).words, ["cat"]);
+    }
+
+    #[test]
+    fn test_parse_sequence_pipeline_single_command() {
+        let commands = first_pipeline("ls -la");
+        assert_eq!(commands.len(), 1);
+        assert_eq!(simple(&commands[0]).words, ["ls", "-la"]);
+    }
+
+    #[test]
+    fn test_parse_sequence_pipeline_multiple_stages() {
+        let commands = first_pipeline("cat file.txt|grep error | sort");
+        let words: Vec<&Vec<String>> = commands.iter().map(|c| &simple(c).words).collect();
+        assert_eq!(words, [&vec!["cat", "file.txt"], &vec!["grep", "error"], &vec!["sort"]]);
+    }
+
+    #[test]
+    fn test_parse_sequence_blank_line() {
+        let sequence = parse_sequence(tokenize("   ").unwrap()).unwrap();
+        assert!(sequence.is_empty());
+    }
+
+    #[test]
+    fn test_parse_sequence_pipeline_empty_segment_is_error() {
+        for input in ["ls |", "| wc -l", "ls | | wc -l"] {
+            let err = parse_sequence(tokenize(input).unwrap()).unwrap_err();
+            assert_eq!(err, ParseError::UnexpectedToken("|".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_set_then_expand_var() {
+        let mut env = ShellEnv::empty();
+        builtin_set(&["FOO", "bar"], &mut env, &mut Vec::new(), &mut Vec::new());
+
+        assert_eq!(expand_args(&["echo", "$FOO"], &env), ["echo", "bar"]);
+    }
+
+    #[test]
+    fn test_expand_braced_var_with_suffix() {
+        let mut env = ShellEnv::empty();
+        env.set_var("NAME", "file");
+
+        assert_eq!(expand_args(&["${NAME}_suffix"], &env), ["file_suffix"]);
+        assert_eq!(expand_args(&["pre$NAME"], &env), ["prefile"]);
+        // Without braces the suffix is part of the name
+        assert!(expand_args(&["$NAME_suffix"], &env).is_empty());
+    }
+
+    #[test]
+    fn test_expand_in_double_quotes_not_single() {
+        let mut env = ShellEnv::empty();
+        env.set_var("FOO", "bar");
+
+        assert_eq!(expand_args(&["\"$FOO baz\""], &env), ["bar baz"]);
+        assert_eq!(expand_args(&["'$FOO'"], &env), ["$FOO"]);
+        assert_eq!(expand_args(&["\"'$FOO'\""], &env), ["'bar'"]);
+    }
+
+    #[test]
+    fn test_expand_vars_defined_and_undefined() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(expand_args(&["$HOME"], &env), ["/home/me"]);
+        assert_eq!(expand_args(&["${HOME}"], &env), ["/home/me"]);
+        assert!(expand_args(&["$NOPE"], &env).is_empty());
+        assert!(expand_args(&["${NOPE}"], &env).is_empty());
+    }
+
+    #[test]
+    fn test_expand_vars_adjacent_text() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+        env.set_var("USER", "me");
+
+        assert_eq!(expand_args(&["${HOME}/bin"], &env), ["/home/me/bin"]);
+        assert_eq!(expand_args(&["$HOME/bin"], &env), ["/home/me/bin"]);
+        assert_eq!(expand_args(&["$USER@$HOME"], &env), ["me@/home/me"]);
+        assert_eq!(expand_args(&["x${USER}y"], &env), ["xmey"]);
+    }
+
+    #[test]
+    fn test_expand_vars_escaped_dollar() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(expand_args(&["\\$HOME"], &env), ["$HOME"]);
+        assert_eq!(expand_args(&["\"\\${HOME}\""], &env), ["${HOME}"]);
+    }
+
+    #[test]
+    fn test_expand_vars_last_status() {
+        let mut env = ShellEnv::empty();
+        env.last_status = 42;
+
+        assert_eq!(expand_args(&["$?"], &env), ["42"]);
+        assert_eq!(expand_args(&["rc=$?."], &env), ["rc=42."]);
+        assert_eq!(expand_args(&["'$?'"], &env), ["$?"]);
+    }
+
+    #[test]
+    fn test_tilde_expands_to_home() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(expand_word("~", &env).text, "/home/me");
+        assert_eq!(expand_word("~/sub", &env).text, "/home/me/sub");
+        assert_eq!(expand_word("~/$HOME", &env).text, "/home/me//home/me");
+    }
+
+    #[test]
+    fn test_tilde_only_at_word_start() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(expand_word("foo~bar", &env).text, "foo~bar");
+        assert_eq!(expand_word("a/~", &env).text, "a/~");
+        assert_eq!(words("echo a ~ b"), ["echo", "a", "~", "b"]);
+    }
+
+    #[test]
+    fn test_quoted_tilde_is_literal() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(expand_word("'~'", &env).text, "~");
+        assert_eq!(expand_word("\"~/x\"", &env).text, "~/x");
+        assert_eq!(expand_word("\\~", &env).text, "~");
+    }
+
+    #[test]
+    fn test_tilde_unknown_user_is_left_alone() {
+        let env = ShellEnv::empty();
+
+        assert_eq!(expand_word("~nouser_xyz", &env).text, "~nouser_xyz");
+        assert_eq!(expand_word("~nouser_xyz/docs", &env).text, "~nouser_xyz/docs");
+    }
+
+    #[test]
+    fn test_echo_tilde_forms() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        assert_eq!(builtin_output("echo ~", &mut env), "/home/me\n");
+        assert_eq!(builtin_output("echo ~/foo", &mut env), "/home/me/foo\n");
+        assert_eq!(builtin_output("echo a~b", &mut env), "a~b\n");
+    }
+
+    #[test]
+    fn test_tilde_user_from_passwd() {
+        // Use whichever account is listed first on this machine
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let Some(line) = passwd.lines().find(|line| line.split(':').count() >= 6) else {
+            return;
+        };
+        let fields: Vec<&str> = line.split(':').collect();
+        let env = ShellEnv::empty();
+
+        assert_eq!(expand_word(&format!("~{}", fields[0]), &env).text, fields[5]);
+        assert_eq!(
+            expand_word(&format!("~{}/x", fields[0]), &env).text,
+            format!("{}/x", fields[5])
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_cd_tilde_goes_home() {
+        let original = std::env::current_dir().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", home.path().to_str().unwrap());
+        let builtins = crate::builtins::builtins();
+
+        handle_command("cd ~", &mut env, &builtins, &mut ShellIo::default());
+        let cwd = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&original).unwrap();
+
+        assert_eq!(std::fs::canonicalize(cwd).unwrap(), std::fs::canonicalize(home.path()).unwrap());
+    }
+
+    /// A temp directory holding a few files to glob against
+    fn glob_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.txt", "a.txt", "c.rs", "ab.md", ".hidden.txt"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_glob_star_expands_sorted() {
+        let dir = glob_dir();
+        let base = dir.path().display().to_string();
+
+        let expanded = words(&format!("ls {}/*.txt", base));
+        assert_eq!(
+            expanded,
+            ["ls".to_string(), format!("{}/a.txt", base), format!("{}/b.txt", base)]
+        );
+    }
+
+    #[test]
+    fn test_glob_question_mark_and_brackets() {
+        let dir = glob_dir();
+        let base = dir.path().display().to_string();
+
+        assert_eq!(words(&format!("echo {}/?.rs", base))[1..], [format!("{}/c.rs", base)]);
+        assert_eq!(
+            words(&format!("echo {}/[ab].*", base))[1..],
+            [format!("{}/a.txt", base), format!("{}/b.txt", base)]
+        );
+    }
+
+    #[test]
+    fn test_glob_no_match_is_left_literal() {
+        let dir = glob_dir();
+        let pattern = format!("{}/*.none", dir.path().display());
+
+        assert_eq!(words(&format!("ls {}", pattern)), ["ls".to_string(), pattern]);
+    }
+
+    #[test]
+    fn test_quoted_glob_is_not_expanded() {
+        let dir = glob_dir();
+        let base = dir.path().display().to_string();
+
+        assert_eq!(words(&format!("echo '{}/*.txt'", base))[1], format!("{}/*.txt", base));
+        assert_eq!(words(&format!("echo \"{}\"/*.rs", base))[1], format!("{}/c.rs", base));
+        assert_eq!(words(&format!("echo {}/\\*.txt", base))[1], format!("{}/*.txt", base));
+    }
+
+    #[test]
+    fn test_glob_skips_hidden_files() {
+        let dir = glob_dir();
+        let base = dir.path().display().to_string();
+
+        assert_eq!(words(&format!("echo {}/.*.txt", base))[1..], [format!("{}/.hidden.txt", base)]);
+        assert!(!words(&format!("echo {}/*", base)).iter().any(|w| w.contains(".hidden")));
+    }
+
+    #[test]
+    fn test_glob_from_unquoted_variable() {
+        let dir = glob_dir();
+        let mut env = ShellEnv::empty();
+        let base = dir.path().display().to_string();
+        env.set_var("PAT", &format!("{}/*.rs", base));
+
+        let expanded = expand_args(&["$PAT", "\"$PAT\""], &env);
+        assert_eq!(expanded, [format!("{}/c.rs", base), format!("{}/*.rs", base)]);
+    }
+
+    #[test]
+    fn test_home_from_passwd() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\nalice:x:1000:1000:Alice:/home/alice:/bin/bash\n";
+
+        assert_eq!(home_from_passwd(passwd, "alice").unwrap(), "/home/alice");
+        assert_eq!(home_from_passwd(passwd, "root").unwrap(), "/root");
+        assert_eq!(home_from_passwd(passwd, "bob"), None);
+    }
+
+    #[test]
+    fn test_expand_lone_dollar_is_literal() {
+        let env = ShellEnv::empty();
+
+        assert_eq!(expand_args(&["$"], &env), ["$"]);
+        assert_eq!(expand_args(&["cost$"], &env), ["cost$"]);
+        assert_eq!(expand_args(&["${unclosed"], &env), ["${unclosed"]);
+    }
+
+    #[test]
+    fn test_expand_positional_parameters() {
+        let mut env = ShellEnv::empty();
+        env.args = ["script.lsh", "one", "two"].map(String::from).to_vec();
+        env.args.resize(12, String::new());
+        env.args[11] = "eleven".to_string();
+
+        assert_eq!(expand_args(&["$0 $1 \"$2\""], &env), ["script.lsh one two"]);
+        assert!(expand_args(&["$3"], &env).is_empty());
+        assert_eq!(expand_args(&["$#"], &env), ["11"]);
+        // Only one digit without braces
+        assert_eq!(expand_args(&["$11 ${11}"], &env), ["one1 eleven"]);
+        assert_eq!(expand_args(&["'$1'"], &env), ["$1"]);
+    }
+
+    #[test]
+    fn test_expand_all_positional_parameters() {
+        let mut env = ShellEnv::empty();
+        env.args = ["script.lsh", "one", "two words", "*"].map(String::from).to_vec();
+
+        // `$@` keeps each parameter a word of its own, `$*` joins them
+        assert_eq!(expand_args(&["\"$@\""], &env), ["one", "two words", "*"]);
+        assert_eq!(expand_args(&["[\"$@\"]"], &env), ["[one", "two words", "*]"]);
+        assert_eq!(expand_args(&["\"$*\""], &env), ["one two words *"]);
+        assert_eq!(expand_args(&["\"$# $@\""], &env), ["3 one", "two words", "*"]);
+        env.set_var("IFS", ":");
+        assert_eq!(expand_args(&["\"$*\""], &env), ["one:two words:*"]);
+
+        // With no parameters `"$@"` is no words at all
+        env.args.truncate(1);
+        assert_eq!(expand_args(&["a", "\"$@\"", "b"], &env), ["a", "b"]);
+        assert_eq!(expand_args(&["\"$*\""], &env), [""]);
+    }
+
+    #[test]
+    fn test_expand_pid_and_shell_name() {
+        let env = ShellEnv::empty();
+        let pid = std::process::id().to_string();
+
+        assert_eq!(expand_args(&["$$"], &env), vec![pid.as_str()]);
+        assert_eq!(expand_args(&["\"/tmp/lsh.$$\" $0"], &env), [format!("/tmp/lsh.{} lsh", pid)]);
+        assert_eq!(expand_args(&["'$$'"], &env), ["$$"]);
+    }
+
+    #[test]
+    fn test_last_status_after_external_commands() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.last_status, 1);
+        assert_eq!(expand_args(&["$?"], &env), ["1"]);
+
+        handle_command("true", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.last_status, 0);
+        assert_eq!(expand_args(&["\"status=$?\""], &env), ["status=0"]);
+    }
+
+    /// Run a builtin command line, capturing what it writes to stdout
+    fn builtin_output(input: &str, env: &mut ShellEnv) -> String {
+        let builtins = crate::builtins::builtins();
+        let commands = first_pipeline(input);
+        let expanded = expand_args(&as_str_vec(&simple(&commands[0]).words), env);
+        let (cmd, args) = expanded.split_first().unwrap();
+
+        let mut out = Vec::new();
+        builtins[cmd.as_str()].run(&as_str_vec(args), env, &builtins, &mut out, &mut Vec::new());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_false_then_echo_status() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(builtin_output("echo $?", &mut env), "1\n");
+    }
+
+    #[test]
+    fn test_true_then_echo_status() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false", &mut env, &builtins, &mut ShellIo::default());
+        handle_command("true", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(builtin_output("echo $?", &mut env), "0\n");
+    }
+
+    #[test]
+    fn test_builtin_usage_error_sets_status() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("");
+        handle_command("unset", &mut env, &builtins, &mut io);
+        assert_eq!(String::from_utf8(io.stderr.unwrap()).unwrap(), "usage: unset VAR\n");
+        assert_ne!(env.last_status, 0);
+        assert_eq!(builtin_output("echo $?", &mut env), "2\n");
+
+        // The successful echo resets the status
+        assert_eq!(builtin_output("echo $?", &mut env), "0\n");
+    }
+
+    #[test]
+    fn test_expand_aliases_replaces_first_word() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ll".to_string(), "ls -la".to_string());
+
+        let tokens = expand_aliases(tokenize("ll /tmp | ll").unwrap(), &env).unwrap();
+        assert_eq!(tokens, tokenize("ls -la /tmp | ls -la").unwrap());
+
+        // Only the command word is an alias candidate
+        let tokens = expand_aliases(tokenize("echo ll").unwrap(), &env).unwrap();
+        assert_eq!(tokens, tokenize("echo ll").unwrap());
+    }
+
+    #[test]
+    fn test_expand_aliases_does_not_recurse() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ls".to_string(), "ls -la".to_string());
+
+        let tokens = expand_aliases(tokenize("ls").unwrap(), &env).unwrap();
+        assert_eq!(tokens, tokenize("ls -la").unwrap());
+    }
+
+    #[test]
+    fn test_run_file_defines_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        let rc = dir.path().join(".lshrc");
+        std::fs::write(&rc, "# my aliases\n\nalias ll='ls -la'\n  set GREETING hi\n").unwrap();
+        let mut env = ShellEnv::empty();
+
+        let action = run_file(&rc, &mut env, &crate::builtins::builtins(), &mut ShellIo::default()).unwrap();
+        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(env.aliases["ll"], "ls -la");
+        assert_eq!(env.get_var("GREETING").unwrap(), "hi");
+    }
+
+    #[test]
+    fn test_source_uses_the_commands_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("greet.sh");
+        std::fs::write(&script, "echo hello\nread NAME\necho to $NAME\ncd /nonexistent\n").unwrap();
+        let out = dir.path().join("out");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("you\n");
+        handle_command(&format!("source {} > {}", script.display(), out.display()), &mut env, &builtins, &mut io);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "hello\nto you\n");
+        assert_eq!(io.stdout.unwrap(), b"");
+        assert!(String::from_utf8(io.stderr.unwrap()).unwrap().starts_with("cd: /nonexistent: "));
+
+        let mut io = buffered("me\n");
+        let command = format!("X=\"$(. {} 2>/dev/null)\"; echo \"[$X]\"; source", script.display());
+        handle_command(&command, &mut env, &builtins, &mut io);
+        assert_eq!(io.stdout.unwrap(), b"[hello\nto me]\n");
+        assert_eq!(io.stderr.unwrap(), b"usage: source FILE\n");
+    }
+
+    #[test]
+    fn test_run_lines_stops_at_exit() {
+        let mut env = ShellEnv::empty();
+
+        let action = run_lines("set A 1\nexit\nset B 2\n", &mut env, &crate::builtins::builtins(), &mut ShellIo::default());
+        assert_eq!(action, ShellAction::Exit(0));
+        assert!(env.get_var("A").is_some());
+        assert!(env.get_var("B").is_none());
+    }
+
+    #[test]
+    fn test_run_lines_joins_continued_lines() {
+        let mut env = ShellEnv::empty();
+        let script = "set A \\\n  split\nset B 'two\n# not a comment\nlines'\n";
+
+        run_lines(script, &mut env, &crate::builtins::builtins(), &mut ShellIo::default());
+        assert_eq!(env.get_var("A").unwrap(), "split");
+        assert_eq!(env.get_var("B").unwrap(), "two\n# not a comment\nlines");
+    }
+
+    #[test]
+    fn test_parse_if() {
+        let sequence = parse_sequence(tokenize("if a; then b; elif c\nthen d; e\nelse f; fi && g").unwrap()).unwrap();
+        assert_eq!(sequence.len(), 1);
+        assert_eq!(sequence[0].rest.len(), 1);
+        let Command::Compound(compound) = &sequence[0].first[0] else { panic!("expected an if") };
+        let CompoundCommand::If { branches, else_body } = compound.as_ref() else { panic!("expected an if") };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(branches[1].1.len(), 2);
+        assert_eq!(else_body.as_ref().unwrap().len(), 1);
+        assert_eq!(compound.to_string(), "if a; then b; elif c; then d; e; else f; fi");
+
+        // Keywords only count at the start of a command
+        let sequence = parse_sequence(tokenize("echo if then fi").unwrap()).unwrap();
+        assert_eq!(simple(&sequence[0].first[0]).words, ["echo", "if", "then", "fi"]);
+    }
 
-pub fn handle_command(input: &str, env: &mut ShellEnv, builtins: &BuiltinMap) -> ShellAction {
-    let parts: Vec<&str> = input.split_whitespace().collect();
-    let (cmd, args) = parts.split_first().unwrap();
-    let expanded_args = expand_args(&args, &env);
+    #[test]
+    fn test_parse_if_errors() {
+        for (input, err) in [
+            ("if true; then echo hi", ParseError::MissingKeyword("fi")),
+            ("if true", ParseError::MissingKeyword("then")),
+            ("if true; then echo fi", ParseError::MissingKeyword("fi")),
+            ("if then echo; fi", ParseError::UnexpectedToken("then".to_string())),
+            ("if true; then fi", ParseError::UnexpectedToken("fi".to_string())),
+            ("fi", ParseError::UnexpectedToken("fi".to_string())),
+            ("if true; then a; fi b", ParseError::UnexpectedToken("b".to_string())),
+        ] {
+            assert_eq!(parse_sequence(tokenize(input).unwrap()).unwrap_err(), err, "{}", input);
+        }
+        assert_eq!(
+            ParseError::MissingKeyword("fi").to_string(),
+            "unexpected end of input while looking for `fi'"
+        );
+    }
 
-    // Builtins expect &[&str]
-    let expanded_arg_strs = as_str_vec(&expanded_args);
+    #[test]
+    fn test_if_runs_the_matching_branch() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        let mut run = |input: &str| capture_output(input, &mut env, &builtins, &mut ShellIo::default());
 
-    // Check if command is a builtin
-    if let Some(builtin_fn) = builtins.get(cmd) {
-        builtin_fn(
-            &expanded_arg_strs,
-            env,
-            &mut std::io::stdout(),
-            &mut std::io::stderr(),
-            )
+        assert_eq!(run("if true; then echo yes; else echo no; fi"), "yes");
+        assert_eq!(run("if false; then echo yes; else echo no; fi"), "no");
+        assert_eq!(run("if false; then echo a; elif true; then echo b; else echo c; fi"), "b");
+        assert_eq!(run("if false; then echo a; fi; echo $?"), "0");
+        assert_eq!(run("if true; then false; fi || echo failed"), "failed");
+        assert_eq!(run("if test 2 -gt 1\nthen\n  echo big\nfi"), "big");
     }
-    else
-    {
-        // Otherwise run external command
-        run_external(cmd, &expanded_arg_strs, env)
+
+    #[test]
+    fn test_nested_if() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+        let mut run = |input: &str| capture_output(input, &mut env, &builtins, &mut ShellIo::default());
+
+        let nested = "if true; then if false; then echo a; else echo b; fi; echo c; else echo d; fi";
+        assert_eq!(run(nested), "b\nc");
+        assert_eq!(run("if if false; then true; fi; then echo cond-ok; fi"), "cond-ok");
+        assert_eq!(run("if false\nthen\n  echo x\nelse\n  if true\n  then\n    echo inner\n  fi\nfi"), "inner");
+
+        let sequence = parse_sequence(tokenize(nested).unwrap()).unwrap();
+        assert_eq!(sequence[0].to_string(), nested);
     }
-}
 
+    #[test]
+    fn test_if_keywords_take_aliases() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+        env.aliases.insert("yes".to_string(), "echo yes".to_string());
 
-fn as_str_vec(strings: &[String]) -> Vec<&str> {
-    strings.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-}
+        assert_eq!(capture_output("if true; then yes; fi", &mut env, &builtins, &mut ShellIo::default()), "yes");
+    }
 
-/// Use the environment to expand our argument list
-fn expand_args(args: &[&str], env: &ShellEnv) -> Vec<String> {
-    let mut expanded_args = Vec::new();
+    #[test]
+    fn test_parse_while() {
+        let sequence = parse_sequence(tokenize("while a\ndo b; c; done").unwrap()).unwrap();
+        let Command::Compound(compound) = &sequence[0].first[0] else { panic!("expected a while") };
+        assert_eq!(compound.to_string(), "while a; do b; c; done");
 
-    for arg in args  {
-        if arg.starts_with("$") {
-            if let Some(expanded_arg) = env.get_var(&arg[1..]) {
-                expanded_args.push(expanded_arg.to_string());
-            }
-            else {
-                expanded_args.push(arg.to_string());
-            }
+        for (input, err) in [
+            ("while true; do echo", ParseError::MissingKeyword("done")),
+            ("while true", ParseError::MissingKeyword("do")),
+            ("while true; do done", ParseError::UnexpectedToken("done".to_string())),
+            ("done", ParseError::UnexpectedToken("done".to_string())),
+        ] {
+            assert_eq!(parse_sequence(tokenize(input).unwrap()).unwrap_err(), err, "{}", input);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_while_loops_until_condition_fails() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let output = capture_output(
+            "N=0; while test $N -lt 3; do echo n=$N; N=$((N + 1)); done",
+            &mut env,
+            &builtins,
+            &mut ShellIo::default(),
+        );
+        assert_eq!(output, "n=0\nn=1\nn=2");
+        assert_eq!(env.get_var("N").unwrap(), "3");
+        assert_eq!(env.last_status, 0);
+
+        // A body that never runs leaves the status at 0
+        assert_eq!(capture_output("while test 1 = 2; do echo no; done; echo $?", &mut env, &builtins, &mut ShellIo::default()), "0");
+    }
+
+    #[test]
+    #[serial]
+    fn test_exit_inside_while_propagates() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let action = handle_command("N=0; while test $N -lt 5; do N=$((N + 1)); exit 4; done; N=99", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(action, ShellAction::Exit(4));
+        assert_eq!(env.get_var("N").unwrap(), "1");
+    }
 
+    #[test]
+    #[serial]
+    fn test_until_counts_down() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let output = capture_output(
+            "set N 3; until test $N -eq 0; do echo n=$N; set N $((N - 1)); done",
+            &mut env,
+            &builtins,
+            &mut ShellIo::default(),
+        );
+        assert_eq!(output, "n=3\nn=2\nn=1");
+        assert_eq!(env.get_var("N").unwrap(), "0");
+        assert_eq!(env.last_status, 0);
+
+        let sequence = parse_sequence(tokenize("until a\ndo b; done").unwrap()).unwrap();
+        assert_eq!(sequence[0].to_string(), "until a; do b; done");
+        assert_eq!(
+            parse_sequence(tokenize("until true; do").unwrap()).unwrap_err(),
+            ParseError::MissingKeyword("done")
+        );
+    }
+
+    #[test]
+    fn test_parse_for() {
+        for (input, text) in [
+            ("for x in a 'b c'; do echo $x; done", "for x in a 'b c'; do echo $x; done"),
+            ("for x in\ndo echo; done", "for x in; do echo; done"),
+            ("for x\ndo\n  echo $x\ndone", "for x; do echo $x; done"),
+        ] {
+            let sequence = parse_sequence(tokenize(input).unwrap()).unwrap();
+            assert_eq!(sequence[0].first[0].to_string(), text);
         }
-        else {
-            expanded_args.push(arg.to_string());
+
+        for (input, err) in [
+            ("for x in a b", ParseError::MissingKeyword("do")),
+            ("for x in a b; do echo", ParseError::MissingKeyword("done")),
+            ("for 1x in a; do echo; done", ParseError::UnexpectedToken("1x".to_string())),
+            ("for x in a | b; do echo; done", ParseError::UnexpectedToken("|".to_string())),
+            ("for x in a; echo; done", ParseError::UnexpectedToken("echo".to_string())),
+        ] {
+            assert_eq!(parse_sequence(tokenize(input).unwrap()).unwrap_err(), err, "{}", input);
         }
     }
 
-    expanded_args
-}
+    #[test]
+    #[serial]
+    fn test_for_loops_over_words() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+        env.set_var("B", "b");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let output = capture_output("for x in a $B $(echo c) \"d e\"; do echo [$x]; done", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(output, "[a]\n[b]\n[c]\n[d e]");
+        assert_eq!(env.get_var("x").unwrap(), "d e");
+
+        // With no `in` the loop goes over the positional parameters
+        env.args = ["script", "one", "two"].map(String::from).to_vec();
+        assert_eq!(capture_output("for arg; do echo $arg; done", &mut env, &builtins, &mut ShellIo::default()), "one\ntwo");
+    }
 
     #[test]
-    fn test_expanded_args_no_dollar() {
-        let env = ShellEnv::empty();
-        let args = ["hello"];
-        let expanded_args = expand_args(&args, &env);
+    #[serial]
+    fn test_for_loops_over_glob() {
+        let dir = glob_dir();
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+        env.set_var("DIR", &dir.path().display().to_string());
 
-        assert_eq!(expanded_args.len(), args.len());
-        assert_eq!(expanded_args[0], "hello");
+        let output = capture_output("for f in $DIR/*.txt; do echo found $f; done", &mut env, &builtins, &mut ShellIo::default());
+        let base = dir.path().display();
+        assert_eq!(output, format!("found {}/a.txt\nfound {}/b.txt", base, base));
     }
 
     #[test]
-    fn test_expanded_args_dollar_no_match() {
-        let env = ShellEnv::empty();
-        let args = ["$HELLO"];
-        let expanded_args = expand_args(&args, &env);
+    fn test_run_lines_reads_whole_if() {
+        let mut env = ShellEnv::empty();
+        let script = "set X 1\nif test $X = 1\nthen\n  set R one\nelse\n  set R other\nfi\nset AFTER yes\n";
 
-        assert_eq!(expanded_args.len(), args.len());
-        assert_eq!(expanded_args[0], "$HELLO");
+        run_lines(script, &mut env, &crate::builtins::builtins(), &mut ShellIo::default());
+        assert_eq!(env.get_var("R").unwrap(), "one");
+        assert_eq!(env.get_var("AFTER").unwrap(), "yes");
     }
 
     #[test]
-    fn test_expanded_args_dollar_match() {
+    fn test_run_lines_missing_fi() {
         let mut env = ShellEnv::empty();
-        let args = ["$HELLO"];
-        env.set_var("HELLO", "world");
-        let expanded_args = expand_args(&args, &env);
 
-        assert_eq!(expanded_args.len(), args.len());
-        assert_eq!(expanded_args[0], "world");
+        run_lines("if true; then\n  set R one\n", &mut env, &crate::builtins::builtins(), &mut ShellIo::default());
+        assert!(env.get_var("R").is_none());
+        assert_eq!(env.last_status, 2);
     }
 
     #[test]
-    fn test_expanded_args_mixed() {
+    fn test_run_file_missing_is_an_error() {
         let mut env = ShellEnv::empty();
-        env.set_var("HELLO", "world");
-        env.set_var("THERE", "Rust");
+        assert!(run_file(Path::new("/nonexistent/.lshrc"), &mut env, &crate::builtins::builtins(), &mut ShellIo::default()).is_err());
+    }
 
-        let args = ["say", "$HELLO", "to", "$THERE"];
+    #[test]
+    fn test_expand_aliases_chains() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("ls".to_string(), "ls -F".to_string());
+        env.aliases.insert("ll".to_string(), "ls -l".to_string());
+        env.aliases.insert("both".to_string(), "ll; ll /tmp".to_string());
 
-        let expanded_args = expand_args(&args, &env);
+        let tokens = expand_aliases(tokenize("both").unwrap(), &env).unwrap();
+        assert_eq!(tokens, tokenize("ls -F -l; ls -F -l /tmp").unwrap());
+    }
 
-        assert_eq!(expanded_args.len(), args.len());
-        assert_eq!(expanded_args, ["say", "world", "to", "Rust"]);
+    #[test]
+    fn test_expand_aliases_stops_on_loops() {
+        let mut env = ShellEnv::empty();
+        env.aliases.insert("a".to_string(), "b 1".to_string());
+        env.aliases.insert("b".to_string(), "a 2".to_string());
+
+        let tokens = expand_aliases(tokenize("a").unwrap(), &env).unwrap();
+        assert_eq!(tokens, tokenize("a 2 1").unwrap());
+    }
+
+    #[test]
+    fn test_alias_used_and_removed_through_handle_command() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("alias fail='false'", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.aliases["fail"], "false");
+
+        handle_command("fail", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.last_status, 1);
+
+        handle_command("unalias fail", &mut env, &builtins, &mut ShellIo::default());
+        assert!(!env.aliases.contains_key("fail"));
+    }
+
+    #[test]
+    fn test_last_status_after_pipeline() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("true | false", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_last_status_after_parse_error() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("ls |", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.last_status, 2);
+    }
+
+    #[test]
+    fn test_tokenize_redirections() {
+        let tokens = tokenize("echo hi>out >> log").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("echo".to_string()),
+                Token::Word("hi".to_string()),
+                Token::Redirect(RedirectKind::Out),
+                Token::Word("out".to_string()),
+                Token::Redirect(RedirectKind::Append),
+                Token::Word("log".to_string()),
+            ]
+        );
+
+        // Quoted or escaped operators are plain text
+        assert_eq!(words("echo '>' \\>"), ["echo", ">", ">"]);
+    }
+
+    #[test]
+    fn test_parse_sequence_pipeline_strips_redirections() {
+        let commands = first_pipeline("echo hi > tmpfile | cat");
+        assert_eq!(simple(&commands[0]).words, ["echo", "hi"]);
+        assert_eq!(
+            simple(&commands[0]).redirects,
+            [Redirect { kind: RedirectKind::Out, target: "tmpfile".to_string() }]
+        );
+        assert!(simple(&commands[1]).redirects.is_empty());
+    }
+
+    #[test]
+    fn test_redirection_without_target_is_error() {
+        let err = parse_sequence(tokenize("echo hi >").unwrap()).unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedToken("newline".to_string()));
+
+        let err = parse_sequence(tokenize("echo hi > | cat").unwrap()).unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedToken("|".to_string()));
+    }
+
+    #[test]
+    fn test_redirect_builtin_output_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tmpfile");
+        let path = path.to_str().unwrap();
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("echo hi > {}", path), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hi\n");
+
+        handle_command(&format!("echo more >> {}", path), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hi\nmore\n");
+
+        // A plain > truncates again
+        handle_command(&format!("echo again > {}", path), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "again\n");
+    }
+
+    #[test]
+    fn test_redirect_external_output_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
+        let path = path.to_str().unwrap();
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("printf 'a\\nb\\n' | sort -r > {}", path), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "b\na\n");
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_tokenize_stderr_redirections() {
+        let tokens = tokenize("cmd 2> err 2>>log").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("cmd".to_string()),
+                Token::Redirect(RedirectKind::Err),
+                Token::Word("err".to_string()),
+                Token::Redirect(RedirectKind::ErrAppend),
+                Token::Word("log".to_string()),
+            ]
+        );
+
+        // Only a bare 2 selects stderr
+        assert_eq!(tokenize("echo a2>f").unwrap()[1], Token::Word("a2".to_string()));
+        assert_eq!(tokenize("echo '2'>f").unwrap()[2], Token::Redirect(RedirectKind::Out));
+    }
+
+    #[test]
+    fn test_redirect_stdout_and_stderr_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("outfile");
+        let err = dir.path().join("errfile");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let line = format!(
+            "sh -c 'echo o; echo e 1>&2' 2> {} > {}",
+            err.display(),
+            out.display()
+        );
+        handle_command(&line, &mut env, &builtins, &mut ShellIo::default());
+
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "o\n");
+        assert_eq!(std::fs::read_to_string(&err).unwrap(), "e\n");
+
+        // 2>> appends
+        let line = format!("sh -c 'echo again 1>&2' 2>> {}", err.display());
+        handle_command(&line, &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(&err).unwrap(), "e\nagain\n");
+    }
+
+    #[test]
+    fn test_redirect_builtin_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = dir.path().join("errfile");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("unset 2> {}", err.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(&err).unwrap(), "usage: unset VAR\n");
+    }
+
+    #[test]
+    fn test_redirect_open_failure_does_not_run_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("file");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let action = handle_command(&format!("echo hi > {}", path.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(env.last_status, 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_sequence_splits_on_semicolons() {
+        let sequence = parse_sequence(tokenize("cd /tmp; ls | wc -l;pwd;").unwrap()).unwrap();
+        assert_eq!(sequence.len(), 3);
+        assert_eq!(simple(&sequence[0].first[0]).words, ["cd", "/tmp"]);
+        assert_eq!(sequence[1].first.len(), 2);
+        assert_eq!(simple(&sequence[2].first[0]).words, ["pwd"]);
+    }
+
+    #[test]
+    fn test_parse_sequence_quoted_semicolon_does_not_split() {
+        let sequence = parse_sequence(tokenize("echo 'a;b' \"c;d\" e\\;f").unwrap()).unwrap();
+        assert_eq!(sequence.len(), 1);
+        assert_eq!(words("echo 'a;b' \"c;d\" e\\;f"), ["echo", "a;b", "c;d", "e;f"]);
+    }
+
+    #[test]
+    fn test_parse_sequence_empty_command_is_error() {
+        for input in ["; ls", "ls;; pwd"] {
+            let err = parse_sequence(tokenize(input).unwrap()).unwrap_err();
+            assert_eq!(err, ParseError::UnexpectedToken(";".to_string()), "{}", input);
+        }
+        assert!(parse_sequence(tokenize("ls | ; pwd").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_sequence_runs_builtins_in_order() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let action = handle_command("set A 1; set B $A; unset A", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(env.get_var("A"), None);
+        assert_eq!(env.get_var("B").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_sequence_continues_after_failure() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false; set AFTER yes", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("AFTER").unwrap(), "yes");
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_sequence_stops_at_exit() {
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let action = handle_command("set A 1; exit; set B 2", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(action, ShellAction::Exit(0));
+        assert_eq!(env.get_var("A").unwrap(), "1");
+        assert_eq!(env.get_var("B"), None);
+    }
+
+    #[test]
+    fn test_tokenize_and_or_operators() {
+        let tokens = tokenize("a&&b||c|d").unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("a".to_string()),
+                Token::And,
+                Token::Word("b".to_string()),
+                Token::Or,
+                Token::Word("c".to_string()),
+                Token::Pipe,
+                Token::Word("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_and_or_precedence() {
+        // Pipes bind tighter than && and ||, which bind tighter than ;
+        let sequence = parse_sequence(tokenize("a | b && c || d | e; f").unwrap()).unwrap();
+        assert_eq!(sequence.len(), 2);
+
+        let list = &sequence[0];
+        assert_eq!(list.first.len(), 2);
+        assert_eq!(list.rest.len(), 2);
+        assert_eq!(list.rest[0].0, Connector::And);
+        assert_eq!(simple(&list.rest[0].1[0]).words, ["c"]);
+        assert_eq!(list.rest[1].0, Connector::Or);
+        assert_eq!(list.rest[1].1.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_and_or_missing_operand_is_error() {
+        for (input, token) in [("&& ls", "&&"), ("true &&", "&&"), ("ls || && pwd", "&&"), ("false ||", "||")] {
+            let err = parse_sequence(tokenize(input).unwrap()).unwrap_err();
+            assert_eq!(err, ParseError::UnexpectedToken(token.to_string()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_quoted_and_or_is_literal() {
+        assert_eq!(words("echo '&&' \"||\""), ["echo", "&&", "||"]);
+    }
+
+    #[test]
+    fn test_and_runs_on_success_only() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("true && set YES yes", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("YES").unwrap(), "yes");
+
+        handle_command("false && set NO no", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("NO"), None);
+        assert_eq!(env.last_status, 1);
+    }
+
+    #[test]
+    fn test_or_runs_on_failure_only() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false || set FALLBACK yes", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("FALLBACK").unwrap(), "yes");
+
+        handle_command("true || set SKIPPED yes", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("SKIPPED"), None);
+        assert_eq!(env.last_status, 0);
+    }
+
+    #[test]
+    fn test_and_or_chains() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("true && false || set RECOVERED yes", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("RECOVERED").unwrap(), "yes");
+
+        handle_command("false && set A a || set B b && set C c", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("A"), None);
+        assert_eq!(env.get_var("B").unwrap(), "b");
+        assert_eq!(env.get_var("C").unwrap(), "c");
+    }
+
+    #[test]
+    fn test_and_skips_right_side_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let ran = dir.path().join("ran");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("false && echo ran > {}", ran.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert!(!ran.exists());
+
+        handle_command(&format!("true && echo ran > {}", ran.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(&ran).unwrap(), "ran\n");
+    }
+
+    #[test]
+    fn test_or_skips_right_side_after_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("test -d {} || echo missing > {}", dir.path().display(), missing.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert!(!missing.exists());
+
+        handle_command(&format!("test -f {} || echo missing > {}", missing.display(), missing.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::fs::read_to_string(&missing).unwrap(), "missing\n");
+    }
+
+    #[test]
+    fn test_true_and_false_builtins_print_nothing() {
+        // No PATH, so these can only be the builtins
+        let mut env = ShellEnv::empty();
+        let builtins = crate::builtins::builtins();
+
+        let mut io = buffered("");
+        handle_command("true extra; false", &mut env, &builtins, &mut io);
+        assert_eq!(env.last_status, 1);
+        handle_command("false; true", &mut env, &builtins, &mut io);
+        assert_eq!(env.last_status, 0);
+        assert_eq!(io.stdout.unwrap(), b"");
+        assert_eq!(io.stderr.unwrap(), b"");
+    }
+
+    #[test]
+    #[serial]
+    fn test_mkdir_and_cd() {
+        let original = std::env::current_dir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let foo = dir.path().join("foo");
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command(&format!("mkdir {} && cd {}", foo.display(), foo.display()), &mut env, &builtins, &mut ShellIo::default());
+        let cwd = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&original).unwrap();
+
+        assert_eq!(std::fs::canonicalize(cwd).unwrap(), std::fs::canonicalize(&foo).unwrap());
+
+        // The directory now exists, so mkdir fails and cd is skipped
+        handle_command(&format!("mkdir {} 2> /dev/null && cd {}", foo.display(), foo.display()), &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(std::env::current_dir().unwrap(), original);
+    }
+
+    #[test]
+    fn test_and_or_with_sequence() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("false && set A a; set B b", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.get_var("A"), None);
+        assert_eq!(env.get_var("B").unwrap(), "b");
+    }
+
+    /// Wait for any background jobs a test started
+    fn reap_jobs(env: &mut ShellEnv) {
+        for job in &mut env.jobs {
+            for child in &mut job.children {
+                child.wait().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokenize_background_operator() {
+        let tokens = tokenize("sleep 1 & echo a&&b").unwrap();
+        assert_eq!(tokens[2], Token::Amp);
+        assert_eq!(tokens[5], Token::And);
+    }
+
+    #[test]
+    fn test_parse_sequence_marks_background_lists() {
+        let sequence = parse_sequence(tokenize("sleep 1 & echo hi; true &").unwrap()).unwrap();
+        let background: Vec<bool> = sequence.iter().map(|list| list.background).collect();
+        assert_eq!(background, [true, false, true]);
+
+        let err = parse_sequence(tokenize("& ls").unwrap()).unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedToken("&".to_string()));
+    }
+
+    #[test]
+    fn test_background_job_returns_immediately() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        let start = std::time::Instant::now();
+        let mut io = buffered("");
+        let action = handle_command("sleep 0.1 &", &mut env, &builtins, &mut io);
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+        assert_eq!(String::from_utf8(io.stderr.unwrap()).unwrap(), format!("[1] {}\n", env.jobs[0].pid));
+
+        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(env.last_status, 0);
+        assert_eq!(env.jobs.len(), 1);
+        assert_eq!(env.jobs[0].id, 1);
+        assert_eq!(env.jobs[0].cmdline, "sleep 0.1");
+        assert_eq!(env.jobs[0].pid, env.jobs[0].children[0].id());
+
+        reap_jobs(&mut env);
+    }
+
+    #[test]
+    fn test_background_pipeline_is_one_job() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("sleep 0.1 | cat & sleep 0.1 &", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.jobs.len(), 2);
+        assert_eq!(env.jobs[0].children.len(), 2);
+        assert_eq!(env.jobs[0].cmdline, "sleep 0.1 | cat");
+        assert_eq!(env.jobs[1].id, 2);
+
+        reap_jobs(&mut env);
+    }
+
+    #[test]
+    fn test_jobs_shows_running_background_job() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("sleep 5 &", &mut env, &builtins, &mut ShellIo::default());
+        let output = builtin_output("jobs", &mut env);
+        assert_eq!(output, "[1]+  Running  sleep 5 &\n");
+
+        for job in &mut env.jobs {
+            job.children[0].kill().unwrap();
+        }
+        reap_jobs(&mut env);
+    }
+
+    #[test]
+    fn test_jobs_removes_completed_jobs_after_reporting() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("sleep 0 &", &mut env, &builtins, &mut ShellIo::default());
+        reap_jobs(&mut env);
+
+        assert_eq!(builtin_output("jobs", &mut env), "[1]+  Done  sleep 0\n");
+        assert!(env.jobs.is_empty());
+        assert_eq!(builtin_output("jobs", &mut env), "");
+    }
+
+    #[test]
+    fn test_fg_brings_background_job_to_foreground() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("sh -c 'sleep 0.1; exit 4' &", &mut env, &builtins, &mut ShellIo::default());
+        assert_eq!(env.jobs.len(), 1);
+        assert_eq!(env.last_status, 0);
+
+        let output = builtin_output("fg", &mut env);
+        assert_eq!(output, "sh -c 'sleep 0.1; exit 4'\n");
+        assert!(env.jobs.is_empty());
+        assert_eq!(env.last_status, 4);
+    }
+
+    #[test]
+    fn test_fg_by_job_id_leaves_other_jobs() {
+        let mut env = ShellEnv::new();
+        let builtins = crate::builtins::builtins();
+
+        handle_command("sleep 0 & sleep 0.1 &", &mut env, &builtins, &mut ShellIo::default());
+        builtin_output("fg %1", &mut env);
+
+        let ids: Vec<usize> = env.jobs.iter().map(|job| job.id).collect();
+        assert_eq!(ids, [2]);
+
+        builtin_output("fg %2", &mut env);
+        assert!(env.jobs.is_empty());
+        assert_eq!(env.last_status, 0);
     }
 
     #[test]
@@ -0,0 +1,243 @@
+use std::fs;
+use std::path::Path;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::external::is_executable;
+
+/// Line editor helper that completes command names and paths with Tab
+pub struct LshHelper {
+    /// Names of the builtins, which complete alongside PATH commands
+    builtins: Vec<String>,
+    /// The shell's PATH, refreshed before each prompt since it can change
+    pub path: String,
+    /// The shell's HOME, for completing paths that start with `~`
+    pub home: Option<String>,
+}
+
+impl LshHelper {
+    pub fn new(builtins: Vec<String>) -> Self {
+        LshHelper { builtins, path: String::new(), home: None }
+    }
+}
+
+impl Completer for LshHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| c.is_whitespace() || matches!(c, '|' | ';' | '&'))
+            .map_or(0, |i| i + 1);
+
+        // The command name comes from the builtins and PATH, unless it's
+        // given as a path; arguments are files
+        let word = &before[start..];
+        if is_command_position(&before[..start]) && !word.contains('/') {
+            return Ok((start, complete_command(word, &self.builtins, &self.path)));
+        }
+        Ok((start, complete_path(word, self.home.as_deref())))
+    }
+}
+
+impl Hinter for LshHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LshHelper {}
+
+impl Validator for LshHelper {}
+
+impl Helper for LshHelper {}
+
+/// Does a word following this text name a command? It does at the start
+/// of the line and after a `|`, `;` or `&`.
+fn is_command_position(before: &str) -> bool {
+    before.trim_end().is_empty() || before.trim_end().ends_with(['|', ';', '&'])
+}
+
+/// The builtins and PATH commands starting with `prefix`, sorted and
+/// without duplicates
+pub fn complete_command(prefix: &str, builtins: &[String], path: &str) -> Vec<String> {
+    let mut matches: Vec<String> = builtins
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .cloned()
+        .collect();
+
+    for dir in path.split(':') {
+        let dir = if dir.is_empty() { Path::new(".") } else { Path::new(dir) };
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && is_executable(&entry.path()) {
+                matches.push(name);
+            }
+        }
+    }
+
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+/// The files and directories whose paths start with `word`, looking in
+/// the current directory or the directory part of `word`. Directories end
+/// in `/`, and a leading `~` stands for `home` but is kept as typed.
+pub fn complete_path(word: &str, home: Option<&str>) -> Vec<String> {
+    if word == "~" {
+        return if home.is_some() { vec!["~/".to_string()] } else { Vec::new() };
+    }
+
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let dir = match (dir_part.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => format!("{}/{}", home, rest),
+        _ if dir_part.is_empty() => ".".to_string(),
+        _ => dir_part.to_string(),
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut matches = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Hidden files only show up when asked for
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        let slash = if entry.path().is_dir() { "/" } else { "" };
+        matches.push(format!("{}{}{}", dir_part, name, slash));
+    }
+
+    matches.sort();
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustyline::history::DefaultHistory;
+    use serial_test::serial;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    /// Make a file in `dir` with the given permissions
+    fn touch(dir: &Path, name: &str, mode: u32) {
+        let path = dir.join(name);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_complete_command_from_builtins_and_path() {
+        let bin = tempdir().unwrap();
+        let more = tempdir().unwrap();
+        touch(bin.path(), "exporter", 0o755);
+        touch(bin.path(), "exit-tool", 0o644);
+        touch(more.path(), "exporter", 0o755);
+        touch(more.path(), "Export", 0o755);
+        let path = format!("{}:{}:/nonexistent", bin.path().display(), more.path().display());
+        let builtins = names(&["exit", "export", "echo"]);
+
+        assert_eq!(complete_command("ex", &builtins, &path), ["exit", "export", "exporter"]);
+        assert_eq!(complete_command("E", &builtins, &path), ["Export"]);
+        assert!(complete_command("zz", &builtins, &path).is_empty());
+    }
+
+    #[test]
+    fn test_complete_only_command_names() {
+        let mut helper = LshHelper::new(names(&["echo", "exit"]));
+        helper.path = "/nonexistent".to_string();
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        assert_eq!(helper.complete("ec", 2, &ctx).unwrap(), (0, names(&["echo"])));
+        assert_eq!(helper.complete("ls | e", 6, &ctx).unwrap(), (5, names(&["echo", "exit"])));
+        assert_eq!(helper.complete("true&&ex", 8, &ctx).unwrap(), (6, names(&["exit"])));
+        assert_eq!(helper.complete("echo /nonexistent/ex", 20, &ctx).unwrap(), (5, Vec::new()));
+    }
+
+    #[test]
+    fn test_complete_from_builtin_table() {
+        let table = crate::builtins::builtins();
+        let mut helper = LshHelper::new(table.keys().map(|name| name.to_string()).collect());
+        helper.path = "/nonexistent".to_string();
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        assert_eq!(helper.complete("unal", 4, &ctx).unwrap(), (0, names(&["unalias"])));
+        assert_eq!(helper.complete("echo hi; pu", 11, &ctx).unwrap(), (9, names(&["pushd"])));
+    }
+
+    /// A directory holding `notes.txt`, `.hidden`, `src/` with `main.rs`,
+    /// and `scripts/`
+    fn sample_tree() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        fs::create_dir(dir.path().join("scripts")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_complete_path() {
+        let tree = sample_tree();
+        let base = format!("{}/", tree.path().display());
+
+        let all = complete_path(&base, None);
+        assert_eq!(all, [format!("{}notes.txt", base), format!("{}scripts/", base), format!("{}src/", base)]);
+        assert_eq!(complete_path(&format!("{}s", base), None), [format!("{}scripts/", base), format!("{}src/", base)]);
+        assert_eq!(complete_path(&format!("{}src/m", base), None), [format!("{}src/main.rs", base)]);
+        assert_eq!(complete_path(&format!("{}.h", base), None), [format!("{}.hidden", base)]);
+        assert!(complete_path(&format!("{}missing/", base), None).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_complete_relative_path() {
+        // Tests run from the crate root, apart from those that cd elsewhere
+        assert_eq!(complete_path("src/ma", None), ["src/main.rs"]);
+        assert_eq!(complete_path("Cargo.t", None), ["Cargo.toml"]);
+        assert!(complete_path("", None).contains(&"src/".to_string()));
+    }
+
+    #[test]
+    fn test_complete_path_under_home() {
+        let tree = sample_tree();
+        let home = tree.path().to_str();
+
+        assert_eq!(complete_path("~", home), ["~/"]);
+        assert_eq!(complete_path("~/sr", home), ["~/src/"]);
+        assert_eq!(complete_path("~/src/", home), ["~/src/main.rs"]);
+        assert!(complete_path("~/sr", None).is_empty());
+    }
+
+    #[test]
+    fn test_complete_arguments_as_paths() {
+        let tree = sample_tree();
+        let mut helper = LshHelper::new(names(&["echo"]));
+        helper.home = tree.path().to_str().map(str::to_string);
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        assert_eq!(helper.complete("cat ~/n", 7, &ctx).unwrap(), (4, names(&["~/notes.txt"])));
+        assert_eq!(helper.complete("~/scr", 5, &ctx).unwrap(), (0, names(&["~/scripts/"])));
+    }
+}
@@ -1,20 +1,70 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::PathBuf;
 
-#[derive(Debug, Clone)]
+use crate::color::use_color;
+use crate::jobs::Job;
+
+#[derive(Debug)]
 pub struct ShellEnv {
     pub vars: HashMap<String, String>,
+    /// Names of the variables passed on to child processes
+    pub exported: HashSet<String>,
+    /// Exit status of the most recent command, exposed as `$?`
+    pub last_status: i32,
+    /// Aliases defined with the `alias` builtin, by name
+    pub aliases: HashMap<String, String>,
+    /// Background jobs that haven't been reaped yet
+    pub jobs: Vec<Job>,
+    /// Command history, oldest first, mirrored from the line editor
+    pub history: Vec<String>,
+    /// Positional parameters: `$0` is the shell or script name and `$1`
+    /// on are the script's arguments
+    pub args: Vec<String>,
+    /// Directories saved by `pushd`, most recent last
+    pub dir_stack: Vec<PathBuf>,
+    /// The shell's process id, exposed as `$$`
+    pub pid: u32,
+    /// Whether to colour the prompt, which goes to stdout
+    pub color: bool,
+    /// Whether to colour error messages, which go to stderr
+    pub error_color: bool,
 }
 
 impl  ShellEnv {
     pub fn new() -> Self {
+        let vars: HashMap<String, String> = std::env::vars().collect(); // start with inherited env
         Self {
-            vars: std::env::vars().collect(), // start with inherited env
+            // Everything we inherited stays exported
+            exported: vars.keys().cloned().collect(),
+            vars,
+            last_status: 0,
+            aliases: HashMap::new(),
+            jobs: Vec::new(),
+            history: Vec::new(),
+            args: vec!["lsh".to_string()],
+            dir_stack: Vec::new(),
+            pid: std::process::id(),
+            color: use_color(std::io::stdout().is_terminal(), std::env::var("NO_COLOR").ok().as_deref()),
+            error_color: use_color(std::io::stderr().is_terminal(), std::env::var("NO_COLOR").ok().as_deref()),
         }
     }
 
     #[cfg(test)]
     pub fn empty() -> Self {
-        Self { vars: std::collections::HashMap::new() }
+        Self {
+            vars: HashMap::new(),
+            exported: HashSet::new(),
+            last_status: 0,
+            aliases: HashMap::new(),
+            jobs: Vec::new(),
+            history: Vec::new(),
+            args: vec!["lsh".to_string()],
+            dir_stack: Vec::new(),
+            pid: std::process::id(),
+            color: false,
+            error_color: false,
+        }
     }
 
     pub fn set_var(&mut self, key: &str, value: &str) {
@@ -27,6 +77,26 @@ impl  ShellEnv {
 
     pub fn unset_var(&mut self, key: &str) {
         self.vars.remove(key);
+        self.exported.remove(key);
+    }
+
+    /// The characters that split fields: `IFS`, or space, tab and newline
+    /// when that isn't set
+    pub fn ifs(&self) -> &str {
+        self.get_var("IFS").map_or(" \t\n", |ifs| ifs.as_str())
+    }
+
+    /// Mark a variable to be passed on to child processes
+    pub fn export_var(&mut self, key: &str) {
+        self.exported.insert(key.to_string());
+    }
+
+    /// The variables child processes should see: the exported ones, plus
+    /// PATH, which commands need whether or not it was exported
+    pub fn exported_vars(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.vars
+            .iter()
+            .filter(|(key, _)| self.exported.contains(*key) || *key == "PATH")
     }
 }
 
@@ -57,4 +127,28 @@ mod tests {
         builtin_unset(&["FOO"], &mut env, &mut out, &mut err);
         assert!(env.get_var("FOO").is_none());
     }
+
+    #[test]
+    fn test_only_exported_vars_are_passed_on() {
+        let mut env = ShellEnv::empty();
+        env.set_var("LOCAL", "1");
+        env.set_var("SHARED", "2");
+        env.set_var("PATH", "/bin");
+        env.export_var("SHARED");
+
+        let mut names: Vec<&String> = env.exported_vars().map(|(key, _)| key).collect();
+        names.sort();
+        assert_eq!(names, ["PATH", "SHARED"]);
+    }
+
+    #[test]
+    fn test_unset_clears_export() {
+        let mut env = ShellEnv::empty();
+        env.set_var("FOO", "bar");
+        env.export_var("FOO");
+        env.unset_var("FOO");
+        env.set_var("FOO", "again");
+
+        assert_eq!(env.exported_vars().count(), 0);
+    }
 }
@@ -1,52 +1,319 @@
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread;
 
-use crate::builtins::{ShellAction};
+use crate::color::print_error;
 use crate::environment::ShellEnv;
+use crate::jobs::{owns_terminal, set_foreground};
+use crate::shell_io::ShellIo;
+use crate::signals::set_interrupted;
 
-/// Run an external command (non-builtin)
-pub fn run_external(cmd: &str, args: &[&str], env: &ShellEnv) -> ShellAction {
-    match Command::new(cmd)
-        .args(args)
-        .env_clear()      // <-- clear inherited env first
-        .envs(&env.vars)  // ← Send our environment
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
+/// Files that replace a stage's standard streams
+#[derive(Debug, Default)]
+pub struct Redirections {
+    /// A here-document's body, read from a pipe
+    pub stdin: Option<PipeReader>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+}
+
+/// One command in a pipeline
+#[derive(Debug, Default)]
+pub struct Stage<'a> {
+    pub cmd: &'a str,
+    pub args: Vec<&'a str>,
+    pub redirections: Redirections,
+    /// Variables from `NAME=value` prefixes, set for this command only
+    pub assignments: Vec<(String, String)>,
+}
+
+
+/// Run a pipeline with its ends connected to `io`. The first stage is fed
+/// all of an in-memory stdin, and what reaches an in-memory stdout or
+/// stderr is collected there. Returns the exit status of the final stage.
+pub fn run_pipeline_io(stages: Vec<Stage>, env: &ShellEnv, io: &mut ShellIo) -> i32 {
+    match connect_pipeline(stages, env, io) {
+        Ok(status) => status,
+        Err(e) => {
+            io.error(env, e);
+            1
+        }
+    }
+}
+
+fn connect_pipeline(stages: Vec<Stage>, env: &ShellEnv, io: &mut ShellIo) -> io::Result<i32> {
+    // A here-document on the first stage leaves the input for later
+    let heredoc = stages.first().is_some_and(|stage| stage.redirections.stdin.is_some());
+    let stdin = match io.stdin.as_mut().filter(|_| !heredoc).map(std::mem::take) {
+        Some(input) => Stdio::from(pipe_from(input)?),
+        None => Stdio::inherit(),
+    };
+    let stdout = if io.stdout.is_some() { Stdio::piped() } else { Stdio::inherit() };
+
+    // Every stage's errors share one pipe, drained on another thread so
+    // neither it nor stdout can fill up and stall the pipeline
+    let (errors, stderr) = match io.stderr {
+        Some(_) => {
+            let (mut reader, writer) = io::pipe()?;
+            let errors = thread::spawn(move || {
+                let mut buffer = Vec::new();
+                let _ = reader.read_to_end(&mut buffer);
+                buffer
+            });
+            (Some(errors), Some(writer))
+        }
+        None => (None, None),
+    };
+
+    // With the terminal to itself, Ctrl-C reaches the pipeline but not us
+    let own_group = owns_terminal();
+    let mut children = spawn_pipeline(stages, env, stdin, stdout, stderr.as_ref(), own_group);
+    // Our copy has to go for the reader to see the end
+    drop(stderr);
+    let shell_group = match children.iter().flatten().next() {
+        Some(leader) if own_group => set_foreground(leader.id() as libc::pid_t),
+        _ => None,
+    };
+
+    // Read until every writer is done before waiting, so a full pipe
+    // can't leave the last stage blocked
+    if let Some(output) = &mut io.stdout
+        && let Some(Some(last)) = children.last_mut()
+        && let Some(mut stdout) = last.stdout.take()
     {
-        Ok(mut child) => {
-            let _ = child.wait();
+        let _ = stdout.read_to_end(output);
+    }
+    let status = wait_pipeline(children);
+    if let Some(group) = shell_group {
+        set_foreground(group);
+        // The SIGINT that killed the pipeline never came to us
+        if status == 128 + libc::SIGINT {
+            set_interrupted();
         }
-        Err(err) => {
-            eprintln!("error running '{}': {}", cmd, err);
+    }
+
+    if let (Some(buffer), Some(errors)) = (&mut io.stderr, errors) {
+        buffer.extend(errors.join().unwrap_or_default());
+    }
+    Ok(status)
+}
+
+/// Start a pipe whose read end gives `input`. A thread does the writing
+/// so input bigger than the pipe's buffer can't block us.
+pub fn pipe_from(input: Vec<u8>) -> io::Result<PipeReader> {
+    let (reader, mut writer) = io::pipe()?;
+    thread::spawn(move || {
+        // The reader may exit without reading it all, which is fine
+        let _ = writer.write_all(&input);
+    });
+    Ok(reader)
+}
+
+/// Start every stage of a pipeline without waiting for any of them. The
+/// first stage reads from `stdin` and the last writes to `stdout`, unless
+/// redirected. Errors go to the `stderr` pipe if given, or the terminal.
+/// With `own_group` the pipeline gets a process group of its own, led by
+/// its first process. Stages that failed to start are `None`.
+pub fn spawn_pipeline(
+    stages: Vec<Stage>,
+    env: &ShellEnv,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Option<&PipeWriter>,
+    own_group: bool,
+) -> Vec<Option<Child>> {
+    let mut children: Vec<Option<Child>> = Vec::new();
+    // Until a stage has started there is no group to join, so 0 asks for
+    // a new one with the child as leader
+    let mut pgid = 0;
+    let mut first_stdin = Some(stdin);
+    let mut last_stdout = Some(stdout);
+    let mut prev_stdout: Option<ChildStdout> = None;
+    let count = stages.len();
+
+    for (i, stage) in stages.into_iter().enumerate() {
+        let Stage { cmd, args, redirections, assignments } = stage;
+        let is_last = i == count - 1;
+
+        // The first stage reads the given stdin, later stages read the
+        // previous stage's pipe (or nothing, if that stage failed to start).
+        // A here-document takes the place of either.
+        let piped = prev_stdout.take();
+        let given = first_stdin.take();
+        let stdin = match (redirections.stdin, piped) {
+            (Some(body), _) => Stdio::from(body),
+            (None, Some(out)) => Stdio::from(out),
+            (None, None) => given.unwrap_or_else(Stdio::null),
+        };
+        let stdout = match redirections.stdout {
+            Some(file) => Stdio::from(file),
+            None if is_last => last_stdout.take().unwrap_or_else(Stdio::inherit),
+            None => Stdio::piped(),
+        };
+
+        let child_stderr = match (redirections.stderr, stderr) {
+            (Some(file), _) => Stdio::from(file),
+            (None, Some(pipe)) => pipe.try_clone().map_or_else(|_| Stdio::inherit(), Stdio::from),
+            (None, None) => Stdio::inherit(),
+        };
+
+        let Some(program) = resolve_in_path(cmd, env) else {
+            report(env, stderr, format_args!("{}: command not found", cmd));
+            children.push(None);
+            continue;
+        };
+
+        let mut command = Command::new(program);
+        // Keep argv[0] as the name the user typed, not the resolved path
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::arg0(&mut command, cmd);
+        // Out of the shell's group, Ctrl-C only reaches the pipeline when
+        // it has the terminal, and never a background job
+        #[cfg(unix)]
+        if own_group {
+            std::os::unix::process::CommandExt::process_group(&mut command, pgid);
         }
+
+        match command
+            .args(args)
+            .env_clear()      // <-- clear inherited env first
+            .envs(env.exported_vars())  // ← Send our exported variables
+            .envs(assignments)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(child_stderr)
+            .spawn()
+        {
+            Ok(mut child) => {
+                // The last stage's output, if piped, is for our caller
+                if !is_last {
+                    prev_stdout = child.stdout.take();
+                }
+                if own_group && pgid == 0 {
+                    pgid = child.id() as i32;
+                }
+                children.push(Some(child));
+            }
+            Err(err) => {
+                report(env, stderr, format_args!("{}: {}", cmd, err));
+                children.push(None);
+            }
+        }
+    }
+
+    children
+}
+
+/// Print one of our errors about a pipeline where its stages' go
+fn report(env: &ShellEnv, stderr: Option<&PipeWriter>, message: fmt::Arguments) {
+    match stderr {
+        Some(mut pipe) => {
+            let _ = writeln!(pipe, "lsh: {}", message);
+        }
+        None => print_error(env, message),
+    }
+}
+
+/// Find the program to run for `cmd`. A command containing `/` is a path
+/// already and is used as is; anything else is looked up on `PATH`.
+pub fn resolve_in_path(cmd: &str, env: &ShellEnv) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        return Some(PathBuf::from(cmd));
+    }
+    find_in_path(cmd, env)
+}
+
+/// Search the directories in the shell's `PATH`, in order, for an
+/// executable file called `name`. An empty `PATH` entry means the current
+/// directory.
+pub fn find_in_path(name: &str, env: &ShellEnv) -> Option<PathBuf> {
+    let path = env.get_var("PATH")?;
+    path.split(':')
+        .map(|dir| if dir.is_empty() { Path::new(".") } else { Path::new(dir) })
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Is this path a regular file we're allowed to execute?
+pub fn is_executable(path: &Path) -> bool {
+    let Ok(metadata) = path.metadata() else {
+        return false;
     };
 
-    ShellAction::Continue
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+
+    #[cfg(not(unix))]
+    metadata.is_file()
+}
+
+/// Wait on every stage of a pipeline so no zombies are left behind, and
+/// return the exit status of the last one
+pub fn wait_pipeline(children: Vec<Option<Child>>) -> i32 {
+    let mut status = 0;
+    for child in children {
+        status = match child {
+            Some(mut child) => match child.wait() {
+                Ok(exit) => exit_code(exit),
+                Err(_) => 1,
+            },
+            None => 127,
+        };
+    }
+
+    status
+}
+
+/// Convert a process exit status into a shell status code. A process
+/// killed by a signal reports `128 + signal`, like bash.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    1
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn stage<'a>(cmd: &'a str, args: &[&'a str]) -> Stage<'a> {
+        Stage { cmd, args: args.to_vec(), ..Stage::default() }
+    }
+
     #[test]
-    fn test_run_external_true() {
+    fn test_external_true() {
         let env = ShellEnv::new();
-        let action = run_external("true", &[], &env);
+        let status = run_pipeline_io(vec![stage("true", &[])], &env, &mut ShellIo::default());
 
-        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(status, 0);
     }
 
     #[test]
-    fn test_run_external_missing_command() {
+    fn test_external_missing_command() {
         let env = ShellEnv::new();
-        let action = run_external("definitely_not_a_real_cmd", &[], &env);
+        let status = run_pipeline_io(vec![stage("definitely_not_a_real_cmd", &[])], &env, &mut ShellIo::default());
 
-        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(status, 127);
     }
 
     #[test]
-    fn test_run_external_echo_to_file() {
+    fn test_external_echo_to_file() {
         use tempfile::NamedTempFile;
         use std::fs;
 
@@ -55,41 +322,254 @@ mod tests {
 
         // Send output to file using shell redirection
         let env = ShellEnv::new();
-        run_external("sh", &["-c", &format!("echo hello > {path}")], &env);
+        let script = format!("echo hello > {path}");
+        run_pipeline_io(vec![stage("sh", &["-c", &script])], &env, &mut ShellIo::default());
 
         let contents = fs::read_to_string(file).unwrap();
         assert_eq!(contents.trim(), "hello");
     }
 
     #[test]
-    fn test_run_external_env_propagation() {
+    fn test_external_env_propagation() {
         use tempfile::NamedTempFile;
         use std::fs;
 
         let mut env = ShellEnv::new();
         env.vars.insert("FOO".into(), "BAR".into());
+        env.export_var("FOO");
 
         let file = NamedTempFile::new().unwrap();
         let path = file.path().to_str().unwrap().to_string();
 
-        run_external(
-            "sh",
-            &["-c", &format!("echo $FOO > {path}")],
-            &env,
-        );
+        let script = format!("echo $FOO > {path}");
+        run_pipeline_io(vec![stage("sh", &["-c", &script])], &env, &mut ShellIo::default());
 
         let contents = fs::read_to_string(file).unwrap();
         assert_eq!(contents.trim(), "BAR");
     }
 
+    #[test]
+    fn test_external_only_sees_exported_vars() {
+        use tempfile::NamedTempFile;
+        use std::fs;
+
+        let mut env = ShellEnv::new();
+        env.set_var("LOCAL_ONLY", "hidden");
+
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+        let script = format!("echo \"[$LOCAL_ONLY]\" > {path}");
+
+        run_pipeline_io(vec![stage("sh", &["-c", &script])], &env, &mut ShellIo::default());
+        assert_eq!(fs::read_to_string(file.path()).unwrap().trim(), "[]");
+
+        env.export_var("LOCAL_ONLY");
+        run_pipeline_io(vec![stage("sh", &["-c", &script])], &env, &mut ShellIo::default());
+        assert_eq!(fs::read_to_string(file.path()).unwrap().trim(), "[hidden]");
+    }
+
+
+    #[test]
+    fn test_child_environment_is_the_exported_set() {
+        let mut env = ShellEnv::empty();
+        env.set_var("PATH", "/bin:/usr/bin");
+        env.set_var("TERM", "xterm-test");
+        env.export_var("TERM");
+        env.set_var("LOCAL_ONLY", "hidden");
+
+        let mut stage = stage("env", &[]);
+        stage.assignments.push(("INLINE".to_string(), "1".to_string()));
+        let mut io = ShellIo { stdout: Some(Vec::new()), ..ShellIo::default() };
+        assert_eq!(run_pipeline_io(vec![stage], &env, &mut io), 0);
+
+        // Exactly what we exported, plus the inline assignment
+        let output = String::from_utf8(io.stdout.unwrap()).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["INLINE=1", "PATH=/bin:/usr/bin", "TERM=xterm-test"]);
+    }
 
     #[test]
-    fn test_run_external_error_exit() {
+    fn test_external_error_exit() {
         // on Unix "false" returns exit code 1
         let env = ShellEnv::new();
-        let action = run_external("false", &[], &env);
+        let status = run_pipeline_io(vec![stage("false", &[])], &env, &mut ShellIo::default());
+
+        assert_eq!(status, 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_external_killed_by_signal() {
+        let env = ShellEnv::new();
+        let status = run_pipeline_io(vec![stage("sh", &["-c", "kill -TERM $$"])], &env, &mut ShellIo::default());
+
+        // SIGTERM is 15
+        assert_eq!(status, 128 + 15);
+    }
+
+    #[test]
+    fn test_run_pipeline_connects_stages() {
+        use tempfile::NamedTempFile;
+        use std::fs;
+
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+
+        let env = ShellEnv::new();
+        let stages = vec![
+            stage("printf", &["b\\na\\nc\\n"]),
+            stage("sort", &[]),
+            stage("tee", &[path.as_str()]),
+        ];
+        let status = run_pipeline_io(stages, &env, &mut ShellIo::default());
+
+        assert_eq!(status, 0);
+        let contents = fs::read_to_string(file).unwrap();
+        assert_eq!(contents, "a\nb\nc\n");
+    }
+
+    #[test]
+    fn test_run_pipeline_returns_last_status() {
+        let env = ShellEnv::new();
+
+        let stages = vec![stage("true", &[]), stage("false", &[])];
+        assert_eq!(run_pipeline_io(stages, &env, &mut ShellIo::default()), 1);
+
+        let stages = vec![stage("false", &[]), stage("true", &[])];
+        assert_eq!(run_pipeline_io(stages, &env, &mut ShellIo::default()), 0);
+    }
+
+    #[test]
+    fn test_run_pipeline_missing_command() {
+        let env = ShellEnv::new();
+        let stages = vec![stage("true", &[]), stage("definitely_not_a_real_cmd", &[])];
+
+        assert_eq!(run_pipeline_io(stages, &env, &mut ShellIo::default()), 127);
+    }
+
+    #[test]
+    fn test_spawn_pipeline_does_not_wait() {
+        let env = ShellEnv::new();
+        let stages = vec![stage("sleep", &["0.2"]), stage("cat", &[])];
+
+        let start = std::time::Instant::now();
+        let mut children = spawn_pipeline(stages, &env, Stdio::null(), Stdio::inherit(), None, false);
+        assert!(start.elapsed() < std::time::Duration::from_millis(200));
+        assert!(children.iter_mut().all(|c| c.as_mut().unwrap().try_wait().unwrap().is_none()));
+
+        assert_eq!(wait_pipeline(children), 0);
+    }
+
+    #[test]
+    fn test_background_pipeline_gets_its_own_process_group() {
+        let env = ShellEnv::new();
+        let stages = vec![stage("sleep", &["5"]), stage("sleep", &["5"])];
+
+        let mut children: Vec<Child> =
+            spawn_pipeline(stages, &env, Stdio::null(), Stdio::inherit(), None, true).into_iter().flatten().collect();
+        let leader = children[0].id() as libc::pid_t;
+        // SAFETY: getpgid and getpgrp have no memory-safety requirements
+        let groups: Vec<_> = children.iter().map(|child| unsafe { libc::getpgid(child.id() as libc::pid_t) }).collect();
+        assert_eq!(groups, vec![leader, leader]);
+        assert_ne!(leader, unsafe { libc::getpgrp() });
+
+        for child in &mut children {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_run_pipeline_redirects_stdout_to_file() {
+        use tempfile::NamedTempFile;
+        use std::fs;
+
+        let file = NamedTempFile::new().unwrap();
+        let env = ShellEnv::new();
+
+        let mut stage = stage("echo", &["redirected"]);
+        stage.redirections.stdout = Some(file.reopen().unwrap());
+        let status = run_pipeline_io(vec![stage], &env, &mut ShellIo::default());
+
+        assert_eq!(status, 0);
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), "redirected\n");
+    }
+
+    #[test]
+    fn test_run_pipeline_io_collects_last_stage() {
+        let env = ShellEnv::new();
+        let stages = vec![stage("printf", &["b\\na\\n"]), stage("sort", &[])];
+
+        let mut io = ShellIo { stdout: Some(Vec::new()), ..ShellIo::default() };
+        let status = run_pipeline_io(stages, &env, &mut io);
+        assert_eq!(status, 0);
+        assert_eq!(io.stdout.unwrap(), b"a\nb\n");
+    }
+
+    #[test]
+    fn test_run_pipeline_io_in_memory() {
+        let env = ShellEnv::new();
+        let stages = vec![
+            stage("sort", &[]),
+            stage("sh", &["-c", "cat; echo oops >&2"]),
+            stage("missing-command-xyz", &[]),
+        ];
+
+        let mut io = ShellIo { stdin: Some(b"pear\napple\n".to_vec()), stdout: Some(Vec::new()), stderr: Some(Vec::new()) };
+        assert_eq!(run_pipeline_io(stages, &env, &mut io), 127);
+        assert_eq!(io.stdin.unwrap(), b"", "the input is used up");
+        let errors = String::from_utf8(io.stderr.unwrap()).unwrap();
+        assert!(errors.contains("oops\n"));
+        assert!(errors.contains("lsh: missing-command-xyz: command not found\n"));
+
+        let stages = vec![stage("sort", &[]), stage("tr", &["a-z", "A-Z"])];
+        let mut io = ShellIo { stdin: Some(b"pear\napple\n".to_vec()), stdout: Some(Vec::new()), stderr: Some(Vec::new()) };
+        assert_eq!(run_pipeline_io(stages, &env, &mut io), 0);
+        assert_eq!(io.stdout.unwrap(), b"APPLE\nPEAR\n");
+    }
+
+    /// Write an executable shell script called `name` into `dir`
+    fn write_script(dir: &Path, name: &str, body: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_resolve_in_path_searches_in_order() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        let hello = write_script(second.path(), "hello", "exit 0");
+        // Not executable, so it's skipped
+        std::fs::write(first.path().join("hello"), "").unwrap();
+
+        let mut env = ShellEnv::empty();
+        env.set_var("PATH", &format!("{}:{}", first.path().display(), second.path().display()));
+
+        assert_eq!(resolve_in_path("hello", &env), Some(hello));
+        assert_eq!(resolve_in_path("nope", &env), None);
+    }
+
+    #[test]
+    fn test_resolve_in_path_skips_search_for_paths() {
+        let env = ShellEnv::empty();
+        assert_eq!(resolve_in_path("./run.sh", &env), Some(PathBuf::from("./run.sh")));
+        assert_eq!(resolve_in_path("ls", &env), None);
+    }
+
+    #[test]
+    fn test_external_uses_shell_path() {
+        let dir = tempfile::tempdir().unwrap();
+        write_script(dir.path(), "seven", "exit 7");
+
+        let mut env = ShellEnv::empty();
+        env.set_var("PATH", &dir.path().display().to_string());
 
-        // We don't treat exit codes as fatal yet
-        assert_eq!(action, ShellAction::Continue);
+        assert_eq!(run_pipeline_io(vec![stage("seven", &[])], &env, &mut ShellIo::default()), 7);
+        assert_eq!(run_pipeline_io(vec![stage("true", &[])], &env, &mut ShellIo::default()), 127);
     }
 }
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use crate::environment::ShellEnv;
+
+/// How many entries to keep when HISTSIZE isn't set
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// Where to keep history between sessions: HISTFILE if it's set, or else
+/// `~/.lsh_history`. An empty HISTFILE, or no HOME to fall back on, means
+/// history isn't saved at all.
+pub fn history_file(env: &ShellEnv) -> Option<PathBuf> {
+    match env.get_var("HISTFILE") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => env.get_var("HOME").map(|home| PathBuf::from(home).join(".lsh_history")),
+    }
+}
+
+/// The most history entries to keep, from HISTSIZE
+pub fn history_size(env: &ShellEnv) -> usize {
+    env.get_var("HISTSIZE")
+        .and_then(|size| size.trim().parse().ok())
+        .unwrap_or(DEFAULT_HISTORY_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_file_defaults_to_home() {
+        let mut env = ShellEnv::empty();
+        assert_eq!(history_file(&env), None);
+
+        env.set_var("HOME", "/home/me");
+        assert_eq!(history_file(&env), Some(PathBuf::from("/home/me/.lsh_history")));
+    }
+
+    #[test]
+    fn test_history_file_from_histfile() {
+        let mut env = ShellEnv::empty();
+        env.set_var("HOME", "/home/me");
+
+        env.set_var("HISTFILE", "/tmp/hist");
+        assert_eq!(history_file(&env), Some(PathBuf::from("/tmp/hist")));
+
+        env.set_var("HISTFILE", "");
+        assert_eq!(history_file(&env), None);
+    }
+
+    #[test]
+    fn test_history_size() {
+        let mut env = ShellEnv::empty();
+        assert_eq!(history_size(&env), 1000);
+
+        env.set_var("HISTSIZE", "50");
+        assert_eq!(history_size(&env), 50);
+
+        env.set_var("HISTSIZE", "lots");
+        assert_eq!(history_size(&env), 1000);
+    }
+}
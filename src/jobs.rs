@@ -0,0 +1,217 @@
+use std::io::Write;
+use std::process::Child;
+
+use crate::external::exit_code;
+
+/// A background job started with a trailing `&`
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    /// Process id of the last command in the job's pipeline
+    pub pid: u32,
+    /// Process group of the job: the pid of its first command
+    pub pgid: u32,
+    /// The command line as the user typed it, without the `&`
+    pub cmdline: String,
+    pub children: Vec<Child>,
+}
+
+impl Job {
+    pub fn new(id: usize, cmdline: &str, children: Vec<Child>) -> Self {
+        let pid = children.last().map(|child| child.id()).unwrap_or(0);
+        let pgid = children.first().map(|child| child.id()).unwrap_or(0);
+        Job { id, pid, pgid, cmdline: cmdline.to_string(), children }
+    }
+
+    /// Block until every process in the job has exited, returning the
+    /// status of the last one
+    pub fn wait(&mut self) -> i32 {
+        let mut status = 0;
+        for child in &mut self.children {
+            status = match child.wait() {
+                Ok(exit) => exit_code(exit),
+                Err(_) => 1,
+            };
+        }
+        status
+    }
+
+    /// Check, without blocking, whether every process in the job has exited
+    pub fn is_done(&mut self) -> bool {
+        self.children.iter_mut().all(|child| !matches!(child.try_wait(), Ok(None)))
+    }
+}
+
+/// Hand the terminal on stdin to process group `pgid`, returning the
+/// group that had it. Does nothing, and returns `None`, if stdin isn't a
+/// terminal.
+pub fn set_foreground(pgid: libc::pid_t) -> Option<libc::pid_t> {
+    // SAFETY: the signal sets are initialised by sigemptyset before use,
+    // and the terminal calls have no memory-safety requirements
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 0 {
+            return None;
+        }
+        let previous = libc::tcgetpgrp(libc::STDIN_FILENO);
+        if previous < 0 {
+            return None;
+        }
+
+        // Once the shell isn't in the foreground, taking the terminal back
+        // raises SIGTTOU, which would stop us unless it is blocked
+        let mut block: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::sigprocmask(libc::SIG_BLOCK, &block, &mut old);
+        let result = libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::sigprocmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+
+        (result == 0).then_some(previous)
+    }
+}
+
+/// Is stdin a terminal whose foreground group is the shell's own? Only
+/// then can the shell give the terminal to a command and take it back.
+pub fn owns_terminal() -> bool {
+    // SAFETY: these calls have no memory-safety requirements
+    unsafe { libc::isatty(libc::STDIN_FILENO) != 0 && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+}
+
+/// Remove finished jobs from the table, reporting each one to `out`
+pub fn reap_jobs(jobs: &mut Vec<Job>, out: &mut dyn Write) {
+    let mut i = 0;
+    while i < jobs.len() {
+        if jobs[i].is_done() {
+            let job = jobs.remove(i);
+            let _ = writeln!(out, "[{}]+ Done  {}", job.id, job.cmdline);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Parse a job spec such as `%2` (or a bare `2`) into a job id
+pub fn parse_job_spec(spec: &str) -> Option<usize> {
+    spec.strip_prefix('%').unwrap_or(spec).parse().ok()
+}
+
+/// The id to give the next job: one more than the highest id in use
+pub fn next_job_id(jobs: &[Job]) -> usize {
+    jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_job_pid_is_last_child() {
+        let first = Command::new("true").spawn().unwrap();
+        let last = Command::new("true").spawn().unwrap();
+        let last_pid = last.id();
+
+        let first_pid = first.id();
+        let mut job = Job::new(1, "true | true", vec![first, last]);
+        assert_eq!(job.pid, last_pid);
+        assert_eq!(job.pgid, first_pid);
+        assert_eq!(job.cmdline, "true | true");
+
+        for child in &mut job.children {
+            child.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reap_jobs_reports_finished_jobs() {
+        let done = Command::new("true").spawn().unwrap();
+        let running = Command::new("sleep").arg("5").spawn().unwrap();
+        let mut jobs = vec![Job::new(1, "true", vec![done]), Job::new(2, "sleep 5", vec![running])];
+
+        // Give `true` time to exit
+        jobs[0].children[0].wait().unwrap();
+
+        let mut out = Vec::new();
+        reap_jobs(&mut jobs, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "[1]+ Done  true\n");
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, 2);
+
+        jobs[0].children[0].kill().unwrap();
+        jobs[0].children[0].wait().unwrap();
+    }
+
+    #[test]
+    fn test_reap_jobs_does_not_block_on_running_jobs() {
+        let running = Command::new("sleep").arg("5").spawn().unwrap();
+        let mut jobs = vec![Job::new(1, "sleep 5", vec![running])];
+
+        let start = std::time::Instant::now();
+        let mut out = Vec::new();
+        reap_jobs(&mut jobs, &mut out);
+
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert!(out.is_empty());
+        assert_eq!(jobs.len(), 1);
+
+        jobs[0].children[0].kill().unwrap();
+        jobs[0].children[0].wait().unwrap();
+    }
+
+    #[test]
+    fn test_reap_jobs_handles_already_exited_child() {
+        // The child is gone (and already waited on) before we check
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let mut jobs = vec![Job::new(1, "true", vec![child])];
+
+        let mut out = Vec::new();
+        reap_jobs(&mut jobs, &mut out);
+        assert!(jobs.is_empty());
+
+        // Reaping again is harmless
+        reap_jobs(&mut jobs, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "[1]+ Done  true\n");
+    }
+
+    #[test]
+    fn test_job_with_unfinished_stage_is_not_done() {
+        let done = Command::new("true").spawn().unwrap();
+        let running = Command::new("sleep").arg("5").spawn().unwrap();
+        let mut job = Job::new(1, "true | sleep 5", vec![done, running]);
+
+        assert!(!job.is_done());
+
+        job.children[1].kill().unwrap();
+        job.children[1].wait().unwrap();
+        job.children[0].wait().unwrap();
+        assert!(job.is_done());
+    }
+
+    #[test]
+    fn test_job_wait_returns_last_status() {
+        let first = Command::new("true").spawn().unwrap();
+        let last = Command::new("false").spawn().unwrap();
+        let mut job = Job::new(1, "true | false", vec![first, last]);
+
+        assert_eq!(job.wait(), 1);
+        assert!(job.is_done());
+    }
+
+    #[test]
+    fn test_parse_job_spec() {
+        assert_eq!(parse_job_spec("%1"), Some(1));
+        assert_eq!(parse_job_spec("12"), Some(12));
+        assert_eq!(parse_job_spec("%x"), None);
+        assert_eq!(parse_job_spec("%"), None);
+    }
+
+    #[test]
+    fn test_next_job_id() {
+        assert_eq!(next_job_id(&[]), 1);
+
+        let jobs = vec![Job::new(1, "a", Vec::new()), Job::new(3, "b", Vec::new())];
+        assert_eq!(next_job_id(&jobs), 4);
+    }
+}
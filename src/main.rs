@@ -1,8 +1,11 @@
-use std::fs;
+use std::io::{ErrorKind, IsTerminal};
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
+use clap::Parser;
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::history::DefaultHistory;
+use rustyline::{CompletionType, Config, Editor};
 
 mod builtins;
 use builtins::{builtins, ShellAction, BuiltinMap};
@@ -13,66 +16,246 @@ use environment::{ShellEnv};
 mod welcome;
 use welcome::print_welcome;
 
+mod arith;
+
+mod color;
+use color::print_error;
+
 mod command_processor;
-use command_processor::handle_command;
+use command_processor::{expand_history, handle_command, read_complete_command, run_file, should_record_history};
+
+mod completion;
+use completion::LshHelper;
 
 mod external;
 
+mod history;
+use history::{history_file, history_size};
+
+mod jobs;
+use jobs::reap_jobs;
+
+mod prompt;
+use prompt::{render_continuation_prompt, render_prompt};
+
+mod shell_io;
+use shell_io::ShellIo;
+
+mod signals;
+use signals::init_signals;
+
+/// A small Unix-like shell
+#[derive(Parser)]
+struct Args {
+    /// Don't run ~/.lshrc at startup
+    #[arg(long)]
+    norc: bool,
+
+    /// Don't print the welcome banner
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Run this command and exit, without starting the prompt
+    #[arg(short = 'c', value_name = "COMMAND")]
+    command: Option<String>,
+
+    /// Run the commands in this file and exit
+    script: Option<PathBuf>,
+
+    /// Arguments for the script, available as $1, $2, ...
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    script_args: Vec<String>,
+}
+
 fn main() -> Result<()> {
-    // Print our welcome message.
-    print_welcome(&mut std::io::stdout());
-
-    // Create our line editor
-    let mut rl = DefaultEditor::new()?;
-
-    // Set up our history with either and existing file
-    // or create a new one.
-    let history_path = "history.txt";
-    match rl.load_history(history_path) {
-        Ok(_) => {}
-        Err(ReadlineError::Io(_)) => {
-            // History file doesn't exist, create it
-            fs::File::create(history_path)?;
+    let args = Args::parse();
+    init_signals();
+
+    // With -c there's no banner or prompt; we exit with the command's status
+    if let Some(command) = &args.command {
+        let mut env = ShellEnv::new();
+        // Like `sh -c`, any further arguments become $0, $1, ...
+        if let Some(name) = &args.script {
+            env.args = std::iter::once(name.display().to_string())
+                .chain(args.script_args.iter().cloned())
+                .collect();
         }
-        Err(err) => {
-            eprintln!("minishell: Error loading history: {}", err);
+        std::process::exit(run_command(command, &mut env, &mut ShellIo::default()));
+    }
+
+    // Likewise for a script; a `#!` line is skipped as a comment
+    if let Some(script) = &args.script {
+        let mut env = ShellEnv::new();
+        env.args = std::iter::once(script.display().to_string())
+            .chain(args.script_args.iter().cloned())
+            .collect();
+        match run_file(script, &mut env, &builtins(), &mut ShellIo::default()) {
+            Ok(action) => std::process::exit(exit_status(&action, &env)),
+            Err(err) => {
+                print_error(&env, format_args!("{}: {}", script.display(), err));
+                std::process::exit(if err.kind() == ErrorKind::NotFound { 127 } else { 126 });
+            }
         }
     }
 
+    // Print our welcome message, unless asked not to or nobody's watching
+    if should_show_welcome(&args, std::io::stdout().is_terminal()) {
+        print_welcome(&mut std::io::stdout());
+    }
+
     // Create our builtin table and our shell environment.
     let builtins = builtins(); // build table once
     let mut env = ShellEnv::new();
 
+    // Set up aliases and variables from the rc file, unless asked not to.
+    // This comes first so it can set HISTSIZE and HISTFILE.
+    let mut action = ShellAction::Continue;
+    if !args.norc {
+        action = run_rc_file(&mut env, &builtins);
+    }
+
+    // Create our line editor; we decide for ourselves which lines to keep
+    let config = Config::builder()
+        .history_ignore_dups(false)?
+        .max_history_size(history_size(&env))?
+        .completion_type(CompletionType::List)
+        .build();
+    let mut rl = Editor::with_config(config)?;
+    rl.set_helper(Some(LshHelper::new(builtins.keys().map(|name| name.to_string()).collect())));
+
+    // Load any history saved by an earlier session. A missing file is
+    // fine; it's created when we save.
+    let history_path = history_file(&env);
+    if let Some(path) = &history_path {
+        match rl.load_history(path) {
+            Ok(_) => {}
+            Err(ReadlineError::Io(err)) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                print_error(&env, format_args!("Error loading history: {}", err));
+            }
+        }
+    }
+    env.history = rl.history().iter().cloned().collect();
+
     // Call our repl loop. This'll run until we get either
     // and exit or cntl-C/cntl-D
-    repl(&mut env, &builtins, &mut rl)?;
+    if action == ShellAction::Continue {
+        action = repl(&mut env, &builtins, &mut rl)?;
+    }
 
-    // Save our history for next time.
-    rl.save_history(history_path)?;
+    // Save our history for next time, making HISTFILE's directory if need be
+    if let Some(path) = &history_path
+        && let Err(err) = create_parent_dir(path).map_err(ReadlineError::Io).and_then(|_| rl.save_history(path))
+    {
+        print_error(&env, format_args!("couldn't save history to {}: {}", path.display(), err));
+    }
 
-    // Exit the shell
+    // Exit the shell, with the code given to `exit` if there was one
     println!("Exiting lsh");
+    std::process::exit(exit_status(&action, &env));
+}
+
+/// The status the shell should finish with: what `exit` was given, or
+/// else the last command's status
+fn exit_status(action: &ShellAction, env: &ShellEnv) -> i32 {
+    match action {
+        ShellAction::Exit(code) => *code,
+        ShellAction::Continue => env.last_status,
+    }
+}
+
+/// Run a `-c` command, giving the status the shell should exit with
+fn run_command(command: &str, env: &mut ShellEnv, io: &mut ShellIo) -> i32 {
+    let action = handle_command(command, env, &builtins(), io);
+    exit_status(&action, env)
+}
+
+/// Make the directory `path` goes in, if it isn't there yet
+fn create_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+/// Is the banner wanted? Only in a terminal, and not with --quiet.
+fn should_show_welcome(args: &Args, is_tty: bool) -> bool {
+    is_tty && !args.quiet
+}
+
+/// Run ~/.lshrc if there is one. A problem reading it is only a warning.
+fn run_rc_file(env: &mut ShellEnv, builtins: &BuiltinMap) -> ShellAction {
+    let Some(home) = env.get_var("HOME") else {
+        return ShellAction::Continue;
+    };
+    let rc_path = Path::new(home).join(".lshrc");
+    if !rc_path.exists() {
+        return ShellAction::Continue;
+    }
 
-    Ok(())
+    match run_file(&rc_path, env, builtins, &mut ShellIo::default()) {
+        Ok(action) => action,
+        Err(err) => {
+            print_error(env, format_args!("warning: couldn't read {}: {}", rc_path.display(), err));
+            ShellAction::Continue
+        }
+    }
 }
 
-fn repl(env: &mut ShellEnv, builtins: &BuiltinMap, rl_editor: &mut DefaultEditor) -> rustyline::Result<()>  {
+fn repl(env: &mut ShellEnv, builtins: &BuiltinMap, rl_editor: &mut Editor<LshHelper, DefaultHistory>) -> rustyline::Result<ShellAction>  {
     loop {
-        let readline = rl_editor.readline(">> ");
+        // Report any background jobs that finished since the last prompt
+        reap_jobs(&mut env.jobs, &mut std::io::stdout());
+
+        // Complete from the PATH and HOME as they are now
+        if let Some(helper) = rl_editor.helper_mut() {
+            helper.path = env.get_var("PATH").cloned().unwrap_or_default();
+            helper.home = env.get_var("HOME").cloned();
+        }
+
+        let readline = rl_editor.readline(&render_prompt(env));
         match readline {
-            Ok(input) => {
-                {
+            Ok(line) => {
+                // Keep reading while a quote is open or the line ends in `\`
+                let Some(input) = read_complete_command(line, || {
+                    rl_editor.readline(&render_continuation_prompt(env)).ok()
+                }) else {
+                    continue;
+                };
+
+                // Swap in any !! or !n history references, showing the result
+                let input = match expand_history(&input, &env.history) {
+                    Ok(Some(expanded)) => {
+                        println!("{}", expanded);
+                        expanded
+                    }
+                    Ok(None) => input,
+                    Err(msg) => {
+                        print_error(env, msg);
+                        env.last_status = 1;
+                        continue;
+                    }
+                };
+
+                // Keep the editor's history and our copy in step
+                if should_record_history(&input, env.history.last().map(String::as_str), env) {
                     rl_editor.add_history_entry(input.as_str())?;
+                    env.history.push(input.clone());
+                }
+
+                if let exit @ ShellAction::Exit(_) = handle_command(&input, env, builtins, &mut ShellIo::default()) {
+                    return Ok(exit);
                 }
 
-                if handle_command(&input, env, builtins) == ShellAction::Exit {
-                    break;
+                // `history -c` clears our copy, so clear the editor's too
+                if env.history.is_empty() {
+                    rl_editor.clear_history()?;
                 }
 
             },
             Err(ReadlineError::Interrupted) => {
-                println!("CTRL-C");
-                break
+                // Ctrl-C throws away the line, not the shell
+                continue
             },
             Err(ReadlineError::Eof) => {
                 println!("CTRL-D");
@@ -85,5 +268,41 @@ fn repl(env: &mut ShellEnv, builtins: &BuiltinMap, rl_editor: &mut DefaultEditor
         }
     }
 
-    Ok(())
+    Ok(ShellAction::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_command_exit_status() {
+        let run = |command| {
+            let mut io = ShellIo { stdin: Some(Vec::new()), stdout: Some(Vec::new()), stderr: Some(Vec::new()) };
+            run_command(command, &mut ShellEnv::new(), &mut io)
+        };
+        assert_eq!(run("exit 2; echo unreachable"), 2);
+        assert_eq!(run("if true; then exit 3; fi"), 3);
+        assert_eq!(run("false"), 1);
+        assert_eq!(run("false; exit"), 1);
+        assert_eq!(run("true"), 0);
+        // An exit inside $(...) only ends the substitution
+        assert_eq!(run("echo $(exit 3); exit"), 0);
+    }
+
+    #[test]
+    fn test_run_command_output() {
+        let mut io = ShellIo { stdin: Some(Vec::new()), stdout: Some(Vec::new()), stderr: Some(Vec::new()) };
+        assert_eq!(run_command("echo hi; no-such-command-here", &mut ShellEnv::new(), &mut io), 127);
+        assert_eq!(io.stdout.unwrap(), b"hi\n");
+        assert_eq!(io.stderr.unwrap(), b"lsh: no-such-command-here: command not found\n");
+    }
+
+    #[test]
+    fn test_should_show_welcome() {
+        assert!(should_show_welcome(&Args::parse_from(["lsh"]), true));
+        assert!(!should_show_welcome(&Args::parse_from(["lsh"]), false));
+        assert!(!should_show_welcome(&Args::parse_from(["lsh", "--quiet"]), true));
+        assert!(!should_show_welcome(&Args::parse_from(["lsh", "-q", "--norc"]), true));
+    }
 }
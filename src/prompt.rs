@@ -0,0 +1,147 @@
+use std::fs;
+
+use crate::color::strip_colors;
+use crate::environment::ShellEnv;
+
+/// The prompt used when PS1 isn't set
+const DEFAULT_PROMPT: &str = ">> ";
+
+/// The prompt for continuation lines when PS2 isn't set
+const DEFAULT_CONTINUATION_PROMPT: &str = "> ";
+
+/// Build the prompt from the PS1 variable, expanding its escapes:
+/// `\w` the working directory (with HOME shown as `~`), `\u` the user,
+/// `\h` the host name up to the first `.`, `\$` a `$` and `\\` a
+/// backslash. `\e` is an escape character for colour codes, and `\[` and
+/// `\]` (which bash needs around them) are dropped. Colour codes are
+/// removed when colour is off. Anything else is left as it is.
+pub fn render_prompt(env: &ShellEnv) -> String {
+    match env.get_var("PS1") {
+        Some(ps1) => expand_prompt(ps1, env),
+        None => DEFAULT_PROMPT.to_string(),
+    }
+}
+
+/// Build the prompt shown while a command continues onto another line,
+/// from PS2 with the same escapes as PS1
+pub fn render_continuation_prompt(env: &ShellEnv) -> String {
+    match env.get_var("PS2") {
+        Some(ps2) => expand_prompt(ps2, env),
+        None => DEFAULT_CONTINUATION_PROMPT.to_string(),
+    }
+}
+
+/// Expand the escapes in a prompt template
+fn expand_prompt(template: &str, env: &ShellEnv) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => prompt.push_str(&working_dir(env)),
+            Some('u') => prompt.push_str(env.get_var("USER").map_or("", |user| user.as_str())),
+            Some('h') => prompt.push_str(&hostname(env)),
+            Some('$') => prompt.push('$'),
+            Some('e') => prompt.push('\x1b'),
+            Some('[' | ']') => {}
+            Some('\\') => prompt.push('\\'),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+
+    if env.color { prompt } else { strip_colors(&prompt) }
+}
+
+/// The current directory, from PWD if `cd` has set it
+fn working_dir(env: &ShellEnv) -> String {
+    let cwd = match env.get_var("PWD") {
+        Some(pwd) => pwd.clone(),
+        None => std::env::current_dir()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default(),
+    };
+
+    // Like bash, show the home directory and anything under it with `~`
+    match env.get_var("HOME") {
+        Some(home) if !home.is_empty() && home != "/" => match cwd.strip_prefix(home.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+            _ => cwd,
+        },
+        _ => cwd,
+    }
+}
+
+/// The short host name, from HOSTNAME if it's set and the kernel if not
+fn hostname(env: &ShellEnv) -> String {
+    let name = match env.get_var("HOSTNAME") {
+        Some(name) => name.clone(),
+        None => fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default(),
+    };
+    name.trim().split('.').next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An environment with everything the prompt escapes look at
+    fn prompt_env(ps1: &str) -> ShellEnv {
+        let mut env = ShellEnv::empty();
+        env.set_var("PS1", ps1);
+        env.set_var("USER", "me");
+        env.set_var("HOSTNAME", "box.example.com");
+        env.set_var("HOME", "/home/me");
+        env.set_var("PWD", "/home/me/src/lsh");
+        env
+    }
+
+    #[test]
+    fn test_default_prompt() {
+        assert_eq!(render_prompt(&ShellEnv::empty()), ">> ");
+    }
+
+    #[test]
+    fn test_prompt_escapes() {
+        assert_eq!(render_prompt(&prompt_env("\\u@\\h:\\w\\$ ")), "me@box:~/src/lsh$ ");
+        assert_eq!(render_prompt(&prompt_env("[\\\\] \\x\\")), "[\\] \\x\\");
+    }
+
+    #[test]
+    fn test_prompt_colors() {
+        let mut env = prompt_env("\\[\\e[1;32m\\]\\u\\[\\e[0m\\]\\$ ");
+        assert_eq!(render_prompt(&env), "me$ ");
+
+        env.color = true;
+        assert_eq!(render_prompt(&env), "\x1b[1;32mme\x1b[0m$ ");
+    }
+
+    #[test]
+    fn test_continuation_prompt() {
+        let mut env = prompt_env("");
+        assert_eq!(render_continuation_prompt(&env), "> ");
+
+        env.set_var("PS2", "\\u... ");
+        assert_eq!(render_continuation_prompt(&env), "me... ");
+    }
+
+    #[test]
+    fn test_prompt_working_dir() {
+        let mut env = prompt_env("\\w");
+        env.set_var("PWD", "/home/me");
+        assert_eq!(render_prompt(&env), "~");
+
+        // Only whole directory names count as being under HOME
+        env.set_var("PWD", "/home/meadow");
+        assert_eq!(render_prompt(&env), "/home/meadow");
+
+        env.set_var("PWD", "/tmp");
+        assert_eq!(render_prompt(&env), "/tmp");
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::color::{paint, print_error, RED};
+use crate::environment::ShellEnv;
+
+/// The standard streams commands run with. Each is the shell's own when
+/// `None`, or else an in-memory buffer, so a whole command line can run
+/// without a terminal, as `$(...)` and the tests do.
+#[derive(Debug, Default)]
+pub struct ShellIo {
+    /// Input that no command has read yet
+    pub stdin: Option<Vec<u8>>,
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl ShellIo {
+    /// Print one of the shell's own `lsh: ...` errors to our stderr
+    pub fn error(&mut self, env: &ShellEnv, message: impl fmt::Display) {
+        match &mut self.stderr {
+            Some(buffer) => {
+                let _ = writeln!(buffer, "lsh: {}", message);
+            }
+            None => print_error(env, message),
+        }
+    }
+
+    /// Tell the user something, such as a job starting, on our stderr
+    pub fn notice(&mut self, message: impl fmt::Display) {
+        match &mut self.stderr {
+            Some(buffer) => {
+                let _ = writeln!(buffer, "{}", message);
+            }
+            None => eprintln!("{}", message),
+        }
+    }
+
+    /// Print an error from a builtin to our stderr, as its own error
+    /// writer would: without the `lsh: `, but red on the terminal
+    pub fn builtin_error(&mut self, env: &ShellEnv, message: impl fmt::Display) {
+        match &mut self.stderr {
+            Some(buffer) => {
+                let _ = writeln!(buffer, "{}", message);
+            }
+            None => eprintln!("{}", paint(&message.to_string(), RED, env.error_color)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_goes_to_buffer() {
+        let env = ShellEnv::empty();
+        let mut io = ShellIo { stdout: Some(Vec::new()), stderr: Some(Vec::new()), ..ShellIo::default() };
+        io.error(&env, "oops");
+        assert_eq!(io.stderr.unwrap(), b"lsh: oops\n");
+        assert_eq!(io.stdout.unwrap(), b"");
+    }
+}
@@ -0,0 +1,101 @@
+//! Signal handling for the shell.
+//!
+//! Ctrl-C should interrupt whatever is running in the foreground, never
+//! the shell itself. There are two cases:
+//!
+//! * At the prompt the line editor has the terminal in raw mode, so Ctrl-C
+//!   arrives as a key press rather than a signal. The editor reports it as
+//!   `ReadlineError::Interrupted` and the repl just starts a new line.
+//! * While a foreground command runs, it has a process group of its own
+//!   and the shell hands it the terminal, as `fg` does for a job, so the
+//!   terminal's SIGINT goes to the command alone. When it dies of that
+//!   signal the shell notes the interrupt as if it had seen it too.
+//!   Without a terminal to hand over, commands stay in the shell's group
+//!   and the shell gets the SIGINT itself. We install a handler that only
+//!   notes the signal, so the shell carries on and waits for the command
+//!   as usual.
+//!
+//! Either way, once the interrupt is noted the rest of the command list,
+//! any loop it is in, and the rest of a script are skipped, with status
+//! 130. Loops check the note too, so Ctrl-C stops them even when their
+//! body is all builtins.
+//!
+//! Background jobs run in process groups of their own and never have the
+//! terminal, so the terminal's SIGINT never reaches them.
+//!
+//! We use a handler rather than ignoring SIGINT because ignored
+//! signals stay ignored across `exec`, while handled ones are reset to the
+//! default. That way every child we start can still be interrupted without
+//! having to undo anything between fork and exec.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when SIGINT arrives, until the next command starts
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Note the signal instead of letting SIGINT kill the shell
+extern "C" fn note_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Has there been a SIGINT since `clear_interrupt`?
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Note a SIGINT the shell didn't get itself, as when Ctrl-C killed a
+/// command that had the terminal to itself
+pub fn set_interrupted() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Forget any earlier SIGINT, before starting a new command
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Set up the shell's own signal handling. Call this once at startup,
+/// before running any commands.
+pub fn init_signals() {
+    // SAFETY: the handler is async-signal-safe (an atomic store) and the
+    // sigaction struct is fully initialised before use
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = note_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Restart interrupted system calls so waits aren't cut short
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::ShellEnv;
+    use crate::external::{run_pipeline_io, Stage};
+    use crate::shell_io::ShellIo;
+
+    use serial_test::serial;
+
+    // The SIGINT goes to the whole test process, so it would stop a loop
+    // running in another test; those are serial too
+    #[test]
+    #[serial]
+    fn test_sigint_spares_shell_but_not_children() {
+        init_signals();
+
+        // The shell survives a SIGINT of its own, and notes it. The note
+        // would stop commands in other tests, so it goes straight away...
+        unsafe {
+            libc::raise(libc::SIGINT);
+        }
+        assert!(interrupted());
+        clear_interrupt();
+
+        // ...while a child still dies from one, reporting 128 + 2
+        let env = ShellEnv::new();
+        let stages = vec![Stage { cmd: "sh", args: vec!["-c", "kill -INT $$"], ..Stage::default() }];
+        assert_eq!(run_pipeline_io(stages, &env, &mut ShellIo::default()), 130);
+    }
+}
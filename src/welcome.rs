@@ -24,6 +24,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn test_welcome() {
         let mut buf = Vec::new();
         let _result = print_welcome(&mut buf);
@@ -0,0 +1,164 @@
+use std::process::{Command, Output};
+
+/// Run `lsh -c command` and collect what it did
+fn run_c(command: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(["-c", command])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_c_runs_command_without_banner() {
+    let output = run_c("echo hi && echo bye");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi\nbye\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_c_exits_with_last_status() {
+    assert_eq!(run_c("true; false").status.code(), Some(1));
+    assert_eq!(run_c("sh -c 'exit 3'").status.code(), Some(3));
+    assert_eq!(run_c("no-such-command-here").status.code(), Some(127));
+}
+
+#[test]
+fn test_c_exit_with_code() {
+    assert_eq!(run_c("exit 3; echo unreachable").status.code(), Some(3));
+    assert_eq!(run_c("false; exit").status.code(), Some(1));
+    assert_eq!(run_c("echo $(exit 3); exit").status.code(), Some(0));
+
+    let output = run_c("exit abc");
+    assert_eq!(output.status.code(), Some(255));
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "exit: abc: numeric argument required\n");
+}
+
+#[test]
+fn test_c_test_drives_and_or() {
+    let output = run_c(
+        "[ -e /definitely/not/here ] || echo missing; \
+         test 3 -lt 10 && echo smaller; \
+         [ \"$HOME\" = \"\" ] && echo empty-home; \
+         [ -n x ]; echo $?",
+    );
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "missing\nsmaller\n0\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_c_runs_pipelines() {
+    let output = run_c("echo one two | wc -w");
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "2");
+}
+
+#[test]
+fn test_c_echo_hi() {
+    let output = run_c("echo hi");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_c_command_substitution() {
+    let output = run_c("echo $(echo hi); set X \"$(echo 'val  ue')\"; echo \"[$X]\"");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi\n[val  ue]\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_c_assignment_prefix_is_not_kept() {
+    let output = run_c("X=1 sh -c 'echo $X'; echo \"[$X]\"; X=2; echo $X");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n[]\n2\n");
+}
+
+#[test]
+fn test_c_extra_arguments_are_positional() {
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(["-c", "echo $0 $# $1", "name", "arg"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "name 1 arg\n");
+}
+
+#[test]
+fn test_c_command_not_found() {
+    let output = run_c("nonsense-command");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "lsh: nonsense-command: command not found\n");
+    assert_eq!(output.status.code(), Some(127));
+}
+
+#[test]
+fn test_c_uses_shell_path() {
+    // Changing PATH inside the shell changes where commands are found
+    let output = run_c("set PATH /nonexistent; ls");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "lsh: ls: command not found\n");
+    assert_eq!(output.status.code(), Some(127));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(["-c", "sh -c 'echo found'"])
+        .env("PATH", "/nonexistent:/bin:/usr/bin")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "found\n");
+}
+
+#[test]
+fn test_c_path_commands_skip_search() {
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(["-c", "/bin/sh -c 'echo direct'"])
+        .env("PATH", "/nonexistent")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "direct\n");
+}
+
+#[test]
+fn test_c_pid_and_shell_name() {
+    let child = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(["-c", "echo $$ \"$0\"; echo $(echo $$)"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let output = child.wait_with_output().unwrap();
+    // A command substitution runs in the same shell, so has the same pid
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{} lsh\n{}\n", pid, pid));
+}
+
+#[test]
+fn test_c_no_color_when_piped() {
+    let output = run_c("no-such-command-here; cd /nonexistent");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("lsh: no-such-command-here: command not found"));
+    assert!(stderr.contains("cd: /nonexistent: "));
+    assert!(!stderr.contains('\x1b'));
+}
+
+#[test]
+fn test_c_export_builds_on_old_value() {
+    let output = run_c("set BASE /usr/bin; export BASE=\"$BASE:/opt/bin\"; sh -c 'echo $BASE'");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "/usr/bin:/opt/bin\n");
+
+    // PATH itself still finds commands after growing
+    let output = run_c("export PATH=\"$PATH:/opt/bin\"; sh -c 'echo $PATH'");
+    let path = String::from_utf8(output.stdout).unwrap();
+    assert!(path.trim_end().ends_with(":/opt/bin"));
+    assert!(path.len() > ":/opt/bin\n".len());
+
+    let output = run_c("X=a; X=$X:b; export X; sh -c 'echo $X'");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a:b\n");
+}
+
+#[test]
+fn test_c_child_sees_exported_vars_only() {
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args([
+            "-c",
+            "set LOCAL hidden; export SHARED=seen; INLINE=once sh -c 'echo $TERM/$SHARED/$INLINE/[$LOCAL]'",
+        ])
+        .env("TERM", "xterm-test")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "xterm-test/seen/once/[]\n");
+}
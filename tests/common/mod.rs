@@ -0,0 +1,40 @@
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use tempfile::tempdir;
+
+/// Start an interactive lsh in `dir`, with `args` and the variables in
+/// `envs`, and feed it `input`. It gets a process group of its own, so a
+/// test can signal it the way a terminal would.
+pub fn spawn_lsh(dir: &Path, args: &[&str], envs: &[(&str, &Path)], input: &str) -> Child {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args(args)
+        .envs(envs.iter().copied())
+        .current_dir(dir)
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child
+}
+
+/// Feed `input` to an interactive lsh session and return what it printed
+pub fn run_lsh(input: &str) -> String {
+    // Run in a scratch directory with its own history file, so the user's
+    // history is left alone
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("history");
+    // Skip the user's own ~/.lshrc so it can't change the results
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], input);
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
@@ -0,0 +1,20 @@
+mod common;
+
+use common::run_lsh;
+
+#[test]
+fn test_if_else_across_lines() {
+    let output = run_lsh("if false\nthen\n  echo yes\nelse\n  echo no\nfi\necho after\nexit\n");
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().position(|line| line.ends_with("no")).unwrap();
+    assert!(lines[start + 1].ends_with("after"));
+    assert!(!output.contains("yes"));
+}
+
+#[test]
+fn test_while_across_lines() {
+    let output = run_lsh("N=0\nwhile [ $N -lt 2 ]\ndo\n  echo tick$N\n  N=$((N + 1))\ndone\nexit\n");
+    let ticks: Vec<&str> = output.lines().filter(|line| line.contains("tick")).collect();
+    assert_eq!(ticks.len(), 2);
+    assert!(ticks[0].ends_with("tick0") && ticks[1].ends_with("tick1"));
+}
@@ -0,0 +1,27 @@
+mod common;
+
+use common::run_lsh;
+
+/// Make a few files in a fresh subdirectory (away from the history file)
+/// and run `commands` there
+fn run_in_files(commands: &str) -> String {
+    run_lsh(&format!("mkdir files; cd files; touch b.txt a.txt c.txt notes.md\n{commands}\nexit\n"))
+}
+
+#[test]
+fn test_echo_glob_expands_sorted() {
+    let output = run_in_files("echo *.txt");
+    assert!(output.lines().any(|line| line == "a.txt b.txt c.txt"));
+}
+
+#[test]
+fn test_glob_with_no_match_stays_literal() {
+    let output = run_in_files("echo *.none");
+    assert!(output.lines().any(|line| line == "*.none"));
+}
+
+#[test]
+fn test_quoted_glob_is_not_expanded() {
+    let output = run_in_files("echo '*.txt' \"?.md\"");
+    assert!(output.lines().any(|line| line == "*.txt ?.md"));
+}
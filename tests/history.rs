@@ -0,0 +1,63 @@
+mod common;
+
+use common::{run_lsh, spawn_lsh};
+use tempfile::tempdir;
+
+#[test]
+fn test_history_lists_session_commands() {
+    let output = run_lsh("echo a\necho b\nhistory\nexit\n");
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().position(|line| *line == "    1  echo a").unwrap();
+    assert_eq!(lines[start + 1], "    2  echo b");
+    assert_eq!(lines[start + 2], "    3  history");
+}
+
+#[test]
+fn test_history_clear_and_last_n() {
+    let output = run_lsh("echo a\nhistory -c\necho b\nhistory 1\nexit\n");
+    // Clearing starts the numbering again, and only the last entry shows
+    assert!(output.lines().any(|line| line == "    2  history 1"));
+    assert!(!output.lines().any(|line| line.ends_with("  echo a") || line.ends_with("  echo b")));
+}
+
+#[test]
+fn test_bang_bang_reruns_previous_command() {
+    let output = run_lsh("echo again\n!!\n!1 && echo ok\nexit\n");
+    let lines: Vec<&str> = output.lines().collect();
+    // The expanded command is shown before it runs
+    let start = lines.iter().position(|line| *line == "again").unwrap();
+    assert_eq!(lines[start + 1..start + 6], ["echo again", "again", "echo again && echo ok", "again", "ok"]);
+}
+
+#[test]
+fn test_missing_history_event_runs_nothing() {
+    let output = run_lsh("echo first\n!99\necho after\nexit\n");
+    assert!(!output.lines().any(|line| line.contains("!99")));
+    assert!(output.lines().any(|line| line == "after"));
+}
+
+#[test]
+fn test_session_exits_with_last_status_after_saving_history() {
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("history");
+    // End of input, not `exit`, ends the session
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], "echo fine\nfalse\n");
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let saved = std::fs::read_to_string(&histfile).unwrap();
+    assert!(saved.lines().any(|line| line == "false"));
+}
+
+#[test]
+fn test_histfile_directories_are_created() {
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("state/lsh/history");
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], "echo remembered\nexit\n");
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.stderr.is_empty());
+    let saved = std::fs::read_to_string(&histfile).unwrap();
+    assert!(saved.lines().any(|line| line == "echo remembered"));
+    assert!(!dir.path().join("history.txt").exists());
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::run_lsh;
+
+#[test]
+fn test_finished_background_job_is_reported() {
+    let output = run_lsh("sleep 0.1 &\nsleep 0.3\nexit\n");
+    assert!(output.lines().any(|line| line == "[1]+ Done  sleep 0.1"));
+}
+
+#[test]
+fn test_background_job_does_not_block_prompt() {
+    // Detach the job from our pipes, or reading the output waits for it
+    let start = std::time::Instant::now();
+    let output = run_lsh("sleep 5 > /dev/null 2> /dev/null &\necho prompt is back\nexit\n");
+
+    assert!(output.lines().any(|line| line == "prompt is back"));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn test_kill_background_job() {
+    let start = std::time::Instant::now();
+    let output = run_lsh("sleep 5 > /dev/null 2> /dev/null &\nkill %1\nsleep 0.2\nexit\n");
+
+    assert!(output.lines().any(|line| line.contains("[1]+ Done  sleep 5")));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
@@ -0,0 +1,17 @@
+mod common;
+
+use common::run_lsh;
+
+#[test]
+fn test_pipe_echo_into_cat() {
+    let output = run_lsh("echo hello | cat\nexit\n");
+    assert!(output.lines().any(|line| line == "hello"));
+}
+
+#[test]
+fn test_pipe_three_stages() {
+    let output = run_lsh("printf 'b\\na\\nc\\n' | sort | head -n 2\nexit\n");
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().position(|line| *line == "a").unwrap();
+    assert_eq!(lines[start + 1], "b");
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::fs;
+
+use common::spawn_lsh;
+use tempfile::tempdir;
+
+/// Start an interactive lsh with HOME pointing at a directory whose
+/// `.lshrc` holds `rc`, feed it `input` and return what it printed
+fn run_with_rc(rc: &str, extra_args: &[&str], input: &str) -> String {
+    let home = tempdir().unwrap();
+    fs::write(home.path().join(".lshrc"), rc).unwrap();
+
+    let child = spawn_lsh(home.path(), extra_args, &[("HOME", home.path())], input);
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_lshrc_runs_at_startup() {
+    let rc = "# startup\nset RC_VAR loaded\nalias greet='echo hi'\n";
+    let output = run_with_rc(rc, &[], "echo [$RC_VAR]\ngreet\nexit\n");
+    assert!(output.lines().any(|line| line == "[loaded]"));
+    assert!(output.lines().any(|line| line == "hi"));
+}
+
+#[test]
+fn test_norc_skips_lshrc() {
+    let output = run_with_rc("set RC_VAR loaded\n", &["--norc"], "echo [$RC_VAR]\nexit\n");
+    assert!(output.lines().any(|line| line == "[]"));
+}
+
+#[test]
+fn test_bad_lshrc_line_does_not_stop_startup() {
+    let output = run_with_rc("echo a | | b\nset RC_VAR still\n", &[], "echo [$RC_VAR]\nexit\n");
+    assert!(output.lines().any(|line| line == "[still]"));
+}
+
+#[test]
+fn test_no_banner_when_not_a_terminal() {
+    let output = run_with_rc("", &[], "echo started\nexit\n");
+    assert!(output.contains("started"));
+    assert!(!output.contains("Welcome"));
+}
+
+#[test]
+fn test_lshrc_can_set_history_variables() {
+    let home = tempdir().unwrap();
+    fs::write(home.path().join(".lshrc"), "set HISTFILE custom_history\nset HISTSIZE 2\n").unwrap();
+
+    let child = spawn_lsh(home.path(), &[], &[("HOME", home.path())], "echo one\necho two\necho three\nexit\n");
+    child.wait_with_output().unwrap();
+
+    let saved = fs::read_to_string(home.path().join("custom_history")).unwrap();
+    let entries: Vec<&str> = saved.lines().filter(|line| !line.starts_with('#')).collect();
+    assert_eq!(entries, ["echo three", "exit"]);
+    assert!(!home.path().join(".lsh_history").exists());
+}
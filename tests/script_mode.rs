@@ -0,0 +1,84 @@
+use std::fs;
+use std::process::{Command, Output};
+
+use tempfile::tempdir;
+
+/// Run `lsh` on a script containing `contents`
+fn run_script(contents: &str) -> Output {
+    let dir = tempdir().unwrap();
+    let script = dir.path().join("script.lsh");
+    fs::write(&script, contents).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .arg(&script)
+        .current_dir(dir.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_script_runs_each_line() {
+    let output = run_script("#!/usr/bin/env lsh\n# greet\nset NAME world\n\necho hello $NAME\necho done\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello world\ndone\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_script_exits_with_final_status() {
+    assert_eq!(run_script("echo start\nfalse\n").status.code(), Some(1));
+    assert_eq!(run_script("false\ntrue\n").status.code(), Some(0));
+}
+
+#[test]
+fn test_script_exit_with_code() {
+    let output = run_script("echo before\nexit 4\necho after\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "before\n");
+    assert_eq!(output.status.code(), Some(4));
+
+    // The code gets out of compound commands too
+    assert_eq!(run_script("if true; then\n  exit 5\nfi\nexit 1\n").status.code(), Some(5));
+    assert_eq!(run_script("while true; do exit 6; done\n").status.code(), Some(6));
+}
+
+#[test]
+fn test_missing_script() {
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .arg("/nonexistent/script.lsh")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(127));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("/nonexistent/script.lsh"));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_script_sees_its_arguments() {
+    let dir = tempdir().unwrap();
+    let script = dir.path().join("args.lsh");
+    fs::write(&script, "echo $# $1 $2\necho [$3]\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args([script.to_str().unwrap(), "first", "-x"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2 first -x\n[]\n");
+}
+
+#[test]
+fn test_script_loops_over_all_arguments() {
+    let dir = tempdir().unwrap();
+    let script = dir.path().join("all.lsh");
+    fs::write(&script, "echo $#: $*\nfor arg in \"$@\"; do echo \"<$arg>\"; done\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_lsh"))
+        .args([script.to_str().unwrap(), "a", "b c"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2: a b c\n<a>\n<b c>\n");
+}
+
+#[test]
+fn test_script_heredoc() {
+    let output = run_script("NAME=world\ncat <<EOF\nhello $NAME\n  bye\nEOF\necho after\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello world\n  bye\nafter\n");
+}
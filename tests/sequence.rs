@@ -0,0 +1,24 @@
+mod common;
+
+use common::run_lsh;
+
+#[test]
+fn test_sequence_prints_in_order() {
+    let output = run_lsh("echo a; echo b\nexit\n");
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().position(|line| *line == "a").unwrap();
+    assert_eq!(lines[start + 1], "b");
+}
+
+#[test]
+fn test_sequence_runs_after_failure() {
+    let output = run_lsh("pwd; false; echo done\nexit\n");
+    assert!(output.lines().any(|line| line == "done"));
+}
+
+#[test]
+fn test_exit_stops_the_sequence() {
+    let output = run_lsh("exit; echo after\necho never\n");
+    assert!(!output.contains("after"));
+    assert!(!output.contains("never"));
+}
@@ -0,0 +1,105 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::spawn_lsh;
+use tempfile::tempdir;
+
+#[test]
+fn test_ctrl_c_interrupts_command_not_shell() {
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("history");
+    let start = Instant::now();
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], "sleep 5\necho still here\nexit\n");
+
+    // Give the sleep time to start, then Ctrl-C the whole group
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGINT);
+    }
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().any(|line| line.ends_with("still here")));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_ctrl_c_stops_builtin_loop() {
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("history");
+    let start = Instant::now();
+    // Nothing here runs a child, so only the shell can notice the Ctrl-C
+    let input = "while true; do set N x; done\necho loop $?\nexit\n";
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], input);
+
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGINT);
+    }
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().any(|line| line.ends_with("loop 130")));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_ctrl_c_stops_rest_of_line() {
+    let dir = tempdir().unwrap();
+    let histfile = dir.path().join("history");
+    let start = Instant::now();
+    let input = "sleep 5; echo after\necho next line\nexit\n";
+    let child = spawn_lsh(dir.path(), &["--norc"], &[("HISTFILE", &histfile)], input);
+
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGINT);
+    }
+
+    // The prompt carries on with the next line, but not this one
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("after"));
+    assert!(stdout.lines().any(|line| line.ends_with("next line")));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_ctrl_c_ends_script() {
+    let dir = tempdir().unwrap();
+    let script = dir.path().join("script.lsh");
+    std::fs::write(&script, "sleep 5\necho after\n").unwrap();
+    let start = Instant::now();
+    let child = spawn_lsh(dir.path(), &[script.to_str().unwrap()], &[], "");
+
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGINT);
+    }
+
+    let output = child.wait_with_output().unwrap();
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("after"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(output.status.code(), Some(130));
+}
+
+#[test]
+fn test_ctrl_c_ends_dash_c_command() {
+    let dir = tempdir().unwrap();
+    let start = Instant::now();
+    let child = spawn_lsh(dir.path(), &["-c", "sleep 5; echo after"], &[], "");
+
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGINT);
+    }
+
+    let output = child.wait_with_output().unwrap();
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("after"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(output.status.code(), Some(130));
+}